@@ -129,3 +129,66 @@ fn pkcs11_encrypt_software() {
         .unwrap();
     assert_eq!(&plaintext_msg[..], &plaintext[..]);
 }
+
+#[cfg(feature = "pkcs11-provider")]
+#[test]
+fn pkcs11_check_imported_keys() {
+    use parsec_client::core::interface::operations::psa_algorithm::AsymmetricEncryption;
+    use parsec_client::core::interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_client::core::interface::requests::ResponseStatus;
+    use picky_asn1::wrapper::IntegerAsn1;
+    use picky_asn1_x509::RSAPublicKey;
+
+    set_config("pkcs11_check_imported_keys.toml");
+    reload_service();
+
+    let mut client = TestClient::new();
+    let key_name = String::from("pkcs11_check_imported_keys");
+    let imported_key_name = String::from("pkcs11_check_imported_keys_imported");
+
+    client
+        .generate_rsa_encryption_keys_rsapkcs1v15crypt(key_name.clone())
+        .unwrap();
+    let public_key = client.export_public_key(key_name).unwrap();
+    client
+        .import_rsa_public_key_for_encryption(imported_key_name.clone(), public_key)
+        .unwrap();
+
+    // Parseable key with a modulus too small to encrypt anything.
+    let bad_key = picky_asn1_der::to_vec(&RSAPublicKey {
+        modulus: IntegerAsn1::from_bytes_be_unsigned(vec![0xC3]),
+        public_exponent: IntegerAsn1::from_bytes_be_unsigned(vec![0x03]),
+    })
+    .unwrap();
+    let bad_key_name = String::from("pkcs11_check_imported_keys_bad");
+    let attributes = Attributes {
+        lifetime: Lifetime::Persistent,
+        key_type: Type::RsaPublicKey,
+        bits: 0,
+        policy: Policy {
+            usage_flags: UsageFlags {
+                sign_hash: false,
+                verify_hash: false,
+                sign_message: false,
+                verify_message: false,
+                export: false,
+                encrypt: true,
+                decrypt: false,
+                cache: false,
+                copy: false,
+                derive: false,
+            },
+            permitted_algorithms: AsymmetricEncryption::RsaPkcs1v15Crypt.into(),
+        },
+    };
+    assert_eq!(
+        client
+            .import_key(bad_key_name.clone(), attributes, bad_key)
+            .unwrap_err(),
+        ResponseStatus::PsaErrorInvalidArgument
+    );
+    // The rejected key must not have been stored.
+    client.generate_rsa_sign_key(bad_key_name).unwrap();
+}
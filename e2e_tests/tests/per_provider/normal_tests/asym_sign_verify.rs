@@ -5,6 +5,8 @@ use parsec_client::core::interface::operations::psa_algorithm::*;
 use parsec_client::core::interface::operations::psa_key_attributes::*;
 use parsec_client::core::interface::requests::ResponseStatus;
 use parsec_client::core::interface::requests::Result;
+#[cfg(any(feature = "pkcs11-provider", feature = "mbed-crypto-provider"))]
+use rand::rngs::OsRng;
 #[cfg(any(feature = "mbed-crypto-provider", feature = "tpm-provider"))]
 use ring::signature::{self, UnparsedPublicKey};
 use rsa::{PaddingScheme, PublicKey, RSAPublicKey};
@@ -318,6 +320,51 @@ fn fail_verify_hash2() -> Result<()> {
     Ok(())
 }
 
+#[cfg(any(feature = "pkcs11-provider", feature = "mbed-crypto-provider"))]
+#[test]
+fn fail_verify_rsa_pss_hash() -> Result<()> {
+    let key_name = String::from("fail_verify_rsa_pss_hash");
+    let mut client = TestClient::new();
+    let alg = AsymmetricSignature::RsaPss {
+        hash_alg: Hash::Sha256.into(),
+    };
+
+    client.generate_key(
+        key_name.clone(),
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::RsaKeyPair,
+            bits: 1024,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: true,
+                    verify_hash: true,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: false,
+                    decrypt: false,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: alg.into(),
+            },
+        },
+    )?;
+
+    let mut signature = client.sign(key_name.clone(), alg, HASH.to_vec())?;
+    client.verify(key_name.clone(), alg, HASH.to_vec(), signature.clone())?;
+
+    // Modify signature
+    signature[4] ^= 1;
+    let status = client
+        .verify(key_name, alg, HASH.to_vec(), signature)
+        .unwrap_err();
+    assert_eq!(status, ResponseStatus::PsaErrorInvalidSignature);
+    Ok(())
+}
+
 #[test]
 fn asym_verify_with_rsa_crate() {
     let key_name = String::from("asym_verify_with_rsa_crate");
@@ -342,6 +389,58 @@ fn asym_verify_with_rsa_crate() {
         .unwrap();
 }
 
+#[cfg(any(feature = "pkcs11-provider", feature = "mbed-crypto-provider"))]
+#[test]
+fn asym_verify_rsa_pss_with_rsa_crate() {
+    let key_name = String::from("asym_verify_rsa_pss_with_rsa_crate");
+    let mut client = TestClient::new();
+    let alg = AsymmetricSignature::RsaPss {
+        hash_alg: Hash::Sha256.into(),
+    };
+
+    client
+        .generate_key(
+            key_name.clone(),
+            Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::RsaKeyPair,
+                bits: 1024,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: true,
+                        sign_message: true,
+                        verify_message: true,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: alg.into(),
+                },
+            },
+        )
+        .unwrap();
+    let pub_key = client.export_public_key(key_name.clone()).unwrap();
+
+    let rsa_pub_key = RSAPublicKey::from_pkcs1(&pub_key).unwrap();
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"Bob wrote this message.");
+    let hash = hasher.finalize().to_vec();
+    let signature = client.sign(key_name, alg, hash.clone()).unwrap();
+
+    rsa_pub_key
+        .verify(
+            PaddingScheme::new_pss_with_salt::<Sha256, _>(OsRng, hash.len()),
+            &hash,
+            &signature,
+        )
+        .unwrap();
+}
+
 #[cfg(any(feature = "mbed-crypto-provider", feature = "tpm-provider"))]
 #[test]
 fn verify_with_ring() {
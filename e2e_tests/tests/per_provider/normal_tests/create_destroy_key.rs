@@ -66,6 +66,29 @@ fn create_destroy_twice() {
     client.destroy_key(key_name_2).unwrap();
 }
 
+#[test]
+fn create_destroy_and_create_again() {
+    let mut client = TestClient::new();
+    let key_name = String::from("create_destroy_and_create_again");
+
+    client.generate_rsa_sign_key(key_name.clone()).unwrap();
+    let first_public_key = client.export_public_key(key_name.clone()).unwrap();
+    client.destroy_key(key_name.clone()).unwrap();
+
+    // Both objects of the first key pair must be gone: the new key pair is a different one and
+    // destroying it destroys all its objects again.
+    client.generate_rsa_sign_key(key_name.clone()).unwrap();
+    assert_ne!(
+        client.export_public_key(key_name.clone()).unwrap(),
+        first_public_key
+    );
+    client.destroy_key(key_name.clone()).unwrap();
+    assert_eq!(
+        client.export_public_key(key_name).unwrap_err(),
+        ResponseStatus::PsaErrorDoesNotExist
+    );
+}
+
 #[test]
 fn generate_public_rsa_check_modulus() {
     // As stated in the operation page, the public exponent of RSA key pair should be 65537
@@ -95,3 +118,15 @@ fn failed_created_key_should_be_removed() {
     // The key should not exist anymore in the KIM
     client.generate_rsa_sign_key(key_name).unwrap();
 }
+
+#[cfg(feature = "pkcs11-provider")]
+#[test]
+fn generate_not_supported_key_type() {
+    let mut client = TestClient::new();
+    let key_name = String::from("generate_not_supported_key_type");
+
+    let status = client.generate_aes_keys_ccm(key_name.clone()).unwrap_err();
+    assert_eq!(status, ResponseStatus::PsaErrorNotSupported);
+    // The key must not have been stored.
+    client.generate_rsa_sign_key(key_name).unwrap();
+}
@@ -81,6 +81,24 @@ fn import_key() -> Result<()> {
     client.import_rsa_public_key(key_name, KEY_DATA.to_vec())
 }
 
+#[test]
+fn import_and_destroy_public_key() -> Result<()> {
+    let mut client = TestClient::new();
+    client.do_not_destroy_keys();
+    let key_name = String::from("import_and_destroy_public_key");
+
+    client.import_rsa_public_key(key_name.clone(), KEY_DATA.to_vec())?;
+    client.destroy_key(key_name.clone())?;
+
+    // The public key object must have been destroyed with the mapping.
+    let status = client
+        .export_public_key(key_name.clone())
+        .expect_err("The key should have been destroyed.");
+    assert_eq!(status, ResponseStatus::PsaErrorDoesNotExist);
+    client.import_rsa_public_key(key_name.clone(), KEY_DATA.to_vec())?;
+    client.destroy_key(key_name)
+}
+
 #[test]
 fn create_and_import_key() -> Result<()> {
     let mut client = TestClient::new();
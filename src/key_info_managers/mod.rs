@@ -16,9 +16,12 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use zeroize::Zeroize;
 
 pub mod on_disk_manager;
+#[cfg(test)]
+pub(crate) mod test_utils;
 
 /// Type of the KeyInfoManager
 #[derive(Copy, Clone, Deserialize, Debug)]
@@ -65,6 +68,10 @@ pub struct KeyInfo {
     pub id: Vec<u8>,
     /// Attributes of a key
     pub attributes: Attributes,
+    /// Time at which the key was generated or imported, in seconds since the UNIX epoch. It is not
+    /// recorded for the keys stored by older versions of the service.
+    #[serde(default)]
+    pub created: Option<u64>,
 }
 
 impl KeyTriple {
@@ -194,6 +201,27 @@ impl KeyInfoManagerClient {
         Ok(bincode::deserialize(&key_info.id)?)
     }
 
+    /// Get the key info stored for a given key triple, with the key ID as stored by the KIM.
+    ///
+    /// # Errors
+    ///
+    /// If the key does not exist, PsaErrorDoesNotExist is returned. If any other error occurs,
+    /// KeyInfoManagerError is returned.
+    pub fn get_key_info(
+        &self,
+        key_triple: &KeyTriple,
+    ) -> parsec_interface::requests::Result<KeyInfo> {
+        let key_info_manager_impl = self
+            .key_info_manager_impl
+            .read()
+            .expect("Key Info Manager lock poisoned");
+        match key_info_manager_impl.get(key_triple) {
+            Ok(Some(key_info)) => Ok(key_info.clone()),
+            Ok(None) => Err(ResponseStatus::PsaErrorDoesNotExist),
+            Err(string) => Err(to_response_status(string)),
+        }
+    }
+
     /// Get the `Attributes` for a given key triple
     ///
     /// # Errors
@@ -216,6 +244,31 @@ impl KeyInfoManagerClient {
         Ok(key_info.attributes)
     }
 
+    /// Get the time at which the key represented by a key triple was generated or imported.
+    /// Returns `None` for the keys stored before creation times were recorded.
+    ///
+    /// # Errors
+    ///
+    /// If the key does not exist, PsaErrorDoesNotExist is returned. If any other error occurs,
+    /// KeyInfoManagerError is returned.
+    pub fn get_key_creation_time(
+        &self,
+        key_triple: &KeyTriple,
+    ) -> parsec_interface::requests::Result<Option<SystemTime>> {
+        let key_info_manager_impl = self
+            .key_info_manager_impl
+            .read()
+            .expect("Key Info Manager lock poisoned");
+        let key_info = match key_info_manager_impl.get(key_triple) {
+            Ok(Some(key_info)) => key_info,
+            Ok(None) => return Err(ResponseStatus::PsaErrorDoesNotExist),
+            Err(string) => return Err(to_response_status(string)),
+        };
+        Ok(key_info
+            .created
+            .map(|created| UNIX_EPOCH + Duration::from_secs(created)))
+    }
+
     /// Get all the key triples for the current provider
     pub fn get_all(&self) -> parsec_interface::requests::Result<Vec<KeyTriple>> {
         let key_info_manager_impl = self
@@ -266,9 +319,14 @@ impl KeyInfoManagerClient {
             .key_info_manager_impl
             .write()
             .expect("Key Info Manager lock poisoned");
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .ok();
         let key_info = KeyInfo {
             id: bincode::serialize(key_id)?,
             attributes,
+            created,
         };
 
         match key_info_manager_impl.insert(key_triple, key_info) {
@@ -278,6 +336,27 @@ impl KeyInfoManagerClient {
         }
     }
 
+    /// Put back the key info of a key triple as it was read or removed, overwriting the current
+    /// one if any. Used to roll back changes which failed half-way.
+    ///
+    /// # Errors
+    ///
+    /// If an error occurs in the KIM, KeyInfoManagerError is returned.
+    pub fn restore_key_info(
+        &self,
+        key_triple: KeyTriple,
+        key_info: KeyInfo,
+    ) -> parsec_interface::requests::Result<()> {
+        let mut key_info_manager_impl = self
+            .key_info_manager_impl
+            .write()
+            .expect("Key Info Manager lock poisoned");
+        let _ = key_info_manager_impl
+            .insert(key_triple, key_info)
+            .map_err(to_response_status)?;
+        Ok(())
+    }
+
     /// Returns a Vec of ApplicationName of clients having keys in the provider.
     ///
     /// # Errors
@@ -302,6 +381,37 @@ impl KeyInfoManagerClient {
         Ok(clients)
     }
 
+    /// Returns a Vec of ApplicationName of clients having keys in the provider, along with the
+    /// number of keys each of them owns.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    pub fn list_clients_key_counts(
+        &self,
+    ) -> parsec_interface::requests::Result<Vec<(ApplicationName, usize)>> {
+        let key_info_manager_impl = self
+            .key_info_manager_impl
+            .read()
+            .expect("Key Info Manager lock poisoned");
+        let key_triples = key_info_manager_impl
+            .get_all(self.provider_id)
+            .map_err(to_response_status)?;
+        let mut clients: Vec<(ApplicationName, usize)> = Vec::new();
+
+        for key_triple in key_triples {
+            match clients
+                .iter_mut()
+                .find(|(app_name, _)| app_name == key_triple.app_name())
+            {
+                Some((_, count)) => *count += 1,
+                None => clients.push((key_triple.app_name().clone(), 1)),
+            }
+        }
+
+        Ok(clients)
+    }
+
     /// Returns a Vec of the KeyInfo objects corresponding to the given application name and
     /// provider ID.
     ///
@@ -404,3 +514,89 @@ impl KeyInfoManagerFactory {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::test_utils::{test_client, test_key_attributes, test_kim_factory};
+    use super::KeyTriple;
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::requests::ProviderID;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn clients_key_counts() {
+        let (factory, path) = test_kim_factory("clients_key_counts");
+        let client = factory.build_client(ProviderID::Pkcs11);
+        let other_provider_client = factory.build_client(ProviderID::MbedCrypto);
+
+        let keys = [("app one", 1), ("app two", 2), ("app three", 3)];
+        for (app_name, key_count) in keys.iter() {
+            for key_index in 0..*key_count {
+                let app_name = ApplicationName::from_name(app_name.to_string());
+                let key_name = format!("key {}", key_index);
+                client
+                    .insert_key_info(
+                        KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key_name.clone()),
+                        &0u32,
+                        test_key_attributes(),
+                    )
+                    .unwrap();
+                other_provider_client
+                    .insert_key_info(
+                        KeyTriple::new(app_name, ProviderID::MbedCrypto, key_name),
+                        &0u32,
+                        test_key_attributes(),
+                    )
+                    .unwrap();
+            }
+        }
+
+        let mut roster: Vec<(String, usize)> = client
+            .list_clients_key_counts()
+            .unwrap()
+            .into_iter()
+            .map(|(app_name, count)| (app_name.to_string(), count))
+            .collect();
+        roster.sort();
+        assert_eq!(
+            roster,
+            vec![
+                ("app one".to_string(), 1),
+                ("app three".to_string(), 3),
+                ("app two".to_string(), 2),
+            ]
+        );
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn creation_time_persists() {
+        let key_triple = KeyTriple::new(
+            ApplicationName::from_name("app".to_string()),
+            ProviderID::Pkcs11,
+            "creation_time_persists".to_string(),
+        );
+
+        let (client, path) = test_client("creation_time_persists");
+        client
+            .insert_key_info(key_triple.clone(), &0u32, test_key_attributes())
+            .unwrap();
+        let creation_time = client.get_key_creation_time(&key_triple).unwrap().unwrap();
+        let age = SystemTime::now()
+            .duration_since(creation_time)
+            .unwrap_or_default();
+        assert!(age < Duration::from_secs(60));
+
+        // The store is read again from disk.
+        let (client, _) = test_client("creation_time_persists");
+        assert_eq!(
+            client.get_key_creation_time(&key_triple).unwrap(),
+            Some(creation_time)
+        );
+        let _ = client.remove_key_info(&key_triple).unwrap();
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
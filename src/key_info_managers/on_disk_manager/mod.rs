@@ -16,7 +16,9 @@ use super::{KeyInfo, KeyTriple, ManageKeyInfo};
 use crate::authenticators::ApplicationName;
 use anyhow::{Context, Result};
 use log::{error, info, warn};
+use parsec_interface::operations::psa_key_attributes::Attributes;
 use parsec_interface::requests::ProviderID;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::OsStr;
@@ -203,10 +205,7 @@ impl OnDiskKeyInfoManager {
                         )
                     })?;
                     let _ = key_info_file.read_to_end(&mut key_info)?;
-                    let key_info = bincode::deserialize(&key_info[..]).map_err(|e| {
-                        format_error!("Error deserializing key info", e);
-                        Error::new(ErrorKind::Other, "error deserializing key info")
-                    })?;
+                    let key_info = deserialize_key_info(&key_info)?;
                     match base64_data_triple_to_key_triple(
                         os_str_to_u8_ref(app_name_dir_path.file_name().expect(
                             "The application name directory path should contain a final component.",
@@ -348,6 +347,30 @@ impl ManageKeyInfo for OnDiskKeyInfoManager {
     }
 }
 
+/// Key info as stored before creation times were recorded.
+#[derive(Deserialize)]
+struct LegacyKeyInfo {
+    id: Vec<u8>,
+    attributes: Attributes,
+}
+
+/// Deserialize a mapping file. Bincode does not fill in missing fields with their default value so
+/// the files written before the creation time was added are read in their legacy format.
+fn deserialize_key_info(key_info: &[u8]) -> std::io::Result<KeyInfo> {
+    bincode::deserialize(key_info)
+        .or_else(|_| {
+            bincode::deserialize(key_info).map(|legacy: LegacyKeyInfo| KeyInfo {
+                id: legacy.id,
+                attributes: legacy.attributes,
+                created: None,
+            })
+        })
+        .map_err(|e| {
+            format_error!("Error deserializing key info", e);
+            Error::new(ErrorKind::Other, "error deserializing key info")
+        })
+}
+
 /// OnDiskKeyInfoManager builder
 #[derive(Debug, Default)]
 pub struct OnDiskKeyInfoManagerBuilder {
@@ -381,7 +404,7 @@ impl OnDiskKeyInfoManagerBuilder {
 #[cfg(test)]
 mod test {
     use super::super::{KeyInfo, KeyTriple, ManageKeyInfo};
-    use super::OnDiskKeyInfoManager;
+    use super::{key_triple_to_base64_filenames, OnDiskKeyInfoManager};
     use crate::authenticators::ApplicationName;
     use parsec_interface::operations::psa_algorithm::{
         Algorithm, AsymmetricSignature, Hash, SignHash,
@@ -424,6 +447,7 @@ mod test {
         KeyInfo {
             id: vec![0x11, 0x22, 0x33],
             attributes: test_key_attributes(),
+            created: None,
         }
     }
 
@@ -505,6 +529,7 @@ mod test {
         let key_info_2 = KeyInfo {
             id: vec![0xaa, 0xbb, 0xcc],
             attributes: test_key_attributes(),
+            created: None,
         };
 
         let _ = manager.insert(key_triple.clone(), key_info_1).unwrap();
@@ -578,6 +603,7 @@ mod test {
         let key_info2 = KeyInfo {
             id: vec![0x12, 0x22, 0x32],
             attributes: test_key_attributes(),
+            created: None,
         };
 
         let app_name3 = ApplicationName::from_name("😈 Application Three 😈".to_string());
@@ -586,6 +612,7 @@ mod test {
         let key_info3 = KeyInfo {
             id: vec![0x13, 0x23, 0x33],
             attributes: test_key_attributes(),
+            created: None,
         };
         {
             let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
@@ -612,6 +639,45 @@ mod test {
         fs::remove_dir_all(path).unwrap();
     }
 
+    #[test]
+    fn legacy_key_info_is_read() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/legacy_key_info_is_read_mappings");
+        let key_triple = new_key_triple("legacy_key_info_is_read".to_string());
+        let mut key_info = test_key_info();
+        key_info.created = Some(1_600_000_000);
+
+        {
+            let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+            let _ = manager
+                .insert(key_triple.clone(), key_info.clone())
+                .unwrap();
+        }
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+            assert_eq!(manager.get(&key_triple).unwrap().unwrap(), &key_info);
+        }
+
+        // Rewrite the mapping as it was stored before creation times were recorded.
+        let (app_name, prov, key_name) = key_triple_to_base64_filenames(&key_triple);
+        let legacy_key_info =
+            bincode::serialize(&(key_info.id.clone(), key_info.attributes)).unwrap();
+        fs::write(
+            path.join(app_name).join(prov).join(key_name),
+            legacy_key_info,
+        )
+        .unwrap();
+        {
+            let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+            let legacy_key_info = manager.get(&key_triple).unwrap().unwrap();
+            assert_eq!(legacy_key_info.id, key_info.id);
+            assert_eq!(legacy_key_info.attributes, key_info.attributes);
+            assert!(legacy_key_info.created.is_none());
+            assert!(manager.remove(&key_triple).unwrap().is_some());
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
     fn new_key_triple(key_name: String) -> KeyTriple {
         KeyTriple::new(
             ApplicationName::from_name("Testing Application 😎".to_string()),
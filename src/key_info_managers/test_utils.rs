@@ -0,0 +1,55 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Fixtures shared by the unit tests using a Key Info Manager
+use super::{
+    KeyInfoManagerClient, KeyInfoManagerConfig, KeyInfoManagerFactory, KeyInfoManagerType,
+};
+use parsec_interface::operations::psa_algorithm::{Algorithm, AsymmetricSignature, Hash};
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, Lifetime, Policy, Type, UsageFlags,
+};
+use parsec_interface::requests::ProviderID;
+
+/// Attributes of a 2048 bits RSA key pair used to sign and verify hashes.
+pub fn test_key_attributes() -> Attributes {
+    Attributes {
+        lifetime: Lifetime::Persistent,
+        key_type: Type::RsaKeyPair,
+        bits: 2048,
+        policy: Policy {
+            usage_flags: UsageFlags {
+                sign_hash: true,
+                verify_hash: true,
+                ..Default::default()
+            },
+            permitted_algorithms: Algorithm::AsymmetricSignature(
+                AsymmetricSignature::RsaPkcs1v15Sign {
+                    hash_alg: Hash::Sha256.into(),
+                },
+            ),
+        },
+    }
+}
+
+/// Build an on-disk Key Info Manager factory storing its mappings in the build directory,
+/// returning it with the path of the mappings.
+///
+/// The name has to be unique among the tests as they run in parallel. Building a factory again
+/// with the same name reads back the mappings stored before.
+pub fn test_kim_factory(name: &str) -> (KeyInfoManagerFactory, String) {
+    let path = format!("{}/{}_mappings", env!("OUT_DIR"), name);
+    let factory = KeyInfoManagerFactory::new(&KeyInfoManagerConfig {
+        name: name.to_string(),
+        manager_type: KeyInfoManagerType::OnDisk,
+        store_path: Some(path.clone()),
+    })
+    .unwrap();
+    (factory, path)
+}
+
+/// Build a PKCS 11 client of an on-disk Key Info Manager, returning it with the path of its
+/// mappings.
+pub fn test_client(name: &str) -> (KeyInfoManagerClient, String) {
+    let (factory, path) = test_kim_factory(name);
+    (factory.build_client(ProviderID::Pkcs11), path)
+}
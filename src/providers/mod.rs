@@ -7,6 +7,7 @@
 //! functionality in the underlying hardware which allows the PSA Crypto operations to be
 //! backed by a hardware root of trust.
 use log::trace;
+use parsec_interface::operations::psa_algorithm::Hash;
 use parsec_interface::requests::{Opcode, ProviderID};
 use serde::Deserialize;
 use std::collections::HashSet;
@@ -57,6 +58,9 @@ pub enum ProviderConfig {
         user_pin: Option<String>,
         /// Control whether public key operations are performed in software
         software_public_operations: Option<bool>,
+        /// Further options of the PKCS 11 provider
+        #[serde(flatten)]
+        options: Box<Pkcs11Config>,
     },
     /// TPM provider configuration
     Tpm {
@@ -93,6 +97,50 @@ pub enum ProviderConfig {
     },
 }
 
+/// Optional settings of the PKCS 11 provider configuration
+#[derive(Deserialize, Debug, Zeroize)]
+#[zeroize(drop)]
+pub struct Pkcs11Config {
+    /// Environment variable to read the user pin from
+    pub user_pin_env: Option<String>,
+    /// File to read the user pin from
+    pub user_pin_file: Option<String>,
+    /// Public exponent of generated RSA keys
+    pub rsa_public_exponent: Option<u32>,
+    /// Check that imported public keys can be used for encryption
+    pub check_imported_keys: Option<bool>,
+    /// Hash algorithms not allowed for signing and verification
+    pub disallowed_hashes: Option<Vec<Hash>>,
+    /// What to do at startup with stored keys missing from the token
+    pub startup_consistency_check: Option<String>,
+    /// Number of threads looking up the stored keys on the token at startup
+    pub startup_consistency_check_threads: Option<usize>,
+    /// What to do when the local key IDs and the Key Info Manager disagree
+    pub key_id_discrepancy: Option<String>,
+    /// Number of successful signature verifications to cache
+    pub verify_cache_size: Option<usize>,
+    /// Maximum length of the names of new keys
+    pub key_name_max_length: Option<usize>,
+    /// Characters not allowed in the names of new keys
+    pub key_name_reserved_characters: Option<String>,
+    /// Number of hashes signed with each key which can not be signed again
+    pub sign_replay_window: Option<usize>,
+    /// Check that the objects of generated keys carry the key ID asked for
+    pub check_generated_key_ids: Option<bool>,
+    /// What to do when no user pin is set
+    pub missing_user_pin: Option<String>,
+    /// Log in again and retry once the operations failing because the session was logged out
+    pub relogin: Option<bool>,
+    /// Check that the key objects on the token have the stored key type and size before using them
+    pub check_key_types: Option<bool>,
+    /// Derive the IDs of new keys from their names instead of picking them randomly
+    pub deterministic_key_ids: Option<bool>,
+    /// Width in bytes of the IDs of new keys
+    pub key_id_width: Option<usize>,
+    /// Only use the keys already on the token, never modify it
+    pub read_only: Option<bool>,
+}
+
 impl ProviderConfig {
     /// Get the name of the Key Info Manager in the provider configuration
     pub fn key_info_manager(&self) -> &String {
@@ -1,16 +1,13 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
-use super::utils::to_response_status;
+use super::utils;
 use super::KeyPairType;
 use super::Provider;
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::KeyTriple;
-use cryptoki::types::mechanism::Mechanism;
 use log::{info, trace};
-use parsec_interface::operations::psa_algorithm::Algorithm;
 use parsec_interface::operations::{psa_asymmetric_decrypt, psa_asymmetric_encrypt};
 use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
-use std::convert::TryFrom;
 
 impl Provider {
     pub(super) fn psa_asymmetric_encrypt_internal(
@@ -19,23 +16,27 @@ impl Provider {
         op: psa_asymmetric_encrypt::Operation,
     ) -> Result<psa_asymmetric_encrypt::Result> {
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, op.key_name.clone());
-        let key_id = self.key_info_store.get_key_id(&key_triple)?;
+        let key_id = self.get_key_id(&key_triple)?;
+        let _key_use = self.key_users.acquire(key_id)?;
         let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
 
         op.validate(key_attributes)?;
+        utils::check_plaintext_len(op.alg, key_attributes.bits, op.plaintext.len())?;
 
-        let mech = Mechanism::try_from(Algorithm::from(op.alg)).map_err(to_response_status)?;
+        let mech = utils::encryption_mechanism(op.alg, op.salt.as_deref().map(|salt| &salt[..]))?;
 
         let session = self.new_session()?;
 
         let key = self.find_key(&session, key_id, KeyPairType::PublicKey)?;
         info!("Located encrypting key.");
+        self.check_key_attributes(&session, key, key_attributes)?;
 
         trace!("Encrypt* commands");
         Ok(psa_asymmetric_encrypt::Result {
-            ciphertext: session
-                .encrypt(&mech, key, &op.plaintext)
-                .map_err(to_response_status)?
+            ciphertext: self
+                .with_session_retry(&session, true, |session| {
+                    session.encrypt(&mech, key, &op.plaintext)
+                })?
                 .into(),
         })
     }
@@ -45,24 +46,29 @@ impl Provider {
         app_name: ApplicationName,
         op: psa_asymmetric_decrypt::Operation,
     ) -> Result<psa_asymmetric_decrypt::Result> {
+        self.check_private_operations_allowed()?;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, op.key_name.clone());
-        let key_id = self.key_info_store.get_key_id(&key_triple)?;
+        let key_id = self.get_key_id(&key_triple)?;
+        let _key_use = self.key_users.acquire(key_id)?;
         let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
 
         op.validate(key_attributes)?;
+        utils::check_ciphertext_len(key_attributes.bits, op.ciphertext.len())?;
 
-        let mech = Mechanism::try_from(Algorithm::from(op.alg)).map_err(to_response_status)?;
+        let mech = utils::encryption_mechanism(op.alg, op.salt.as_deref().map(|salt| &salt[..]))?;
 
         let session = self.new_session()?;
 
         let key = self.find_key(&session, key_id, KeyPairType::PrivateKey)?;
         info!("Located decrypting key.");
+        self.check_key_attributes(&session, key, key_attributes)?;
 
         trace!("Decrypt* command");
         Ok(psa_asymmetric_decrypt::Result {
-            plaintext: session
-                .decrypt(&mech, key, &op.ciphertext)
-                .map_err(to_response_status)?
+            plaintext: self
+                .with_session_retry(&session, true, |session| {
+                    self.with_relogin(session, || session.decrypt(&mech, key, &op.ciphertext))
+                })?
                 .into(),
         })
     }
@@ -76,6 +82,7 @@ impl Provider {
         let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
 
         op.validate(key_attributes)?;
+        utils::check_plaintext_len(op.alg, key_attributes.bits, op.plaintext.len())?;
 
         let alg = op.alg;
         let salt_buff = op.salt.as_ref().map(|salt| salt.as_slice());
@@ -1,44 +1,68 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
 use super::utils::to_response_status;
+use super::verify_cache::VerifiedSignature;
 use super::Provider;
 use super::{utils, KeyPairType};
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::KeyTriple;
 use cryptoki::types::mechanism::Mechanism;
-use log::{info, trace};
-use parsec_interface::operations::psa_algorithm::Algorithm;
+use log::{error, info, trace};
+use parsec_interface::operations::psa_algorithm::{Algorithm, AsymmetricSignature};
 use parsec_interface::operations::{psa_sign_hash, psa_verify_hash};
 use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
 use std::convert::TryFrom;
 
 impl Provider {
+    fn check_hash_allowed(&self, alg: AsymmetricSignature) -> Result<()> {
+        if utils::is_hash_allowed(alg, &self.disallowed_hashes) {
+            Ok(())
+        } else {
+            error!("The hash algorithm of {:?} is disallowed.", alg);
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        }
+    }
+
     pub(super) fn psa_sign_hash_internal(
         &self,
         app_name: ApplicationName,
         op: psa_sign_hash::Operation,
     ) -> Result<psa_sign_hash::Result> {
+        self.check_private_operations_allowed()?;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, op.key_name.clone());
 
-        let key_id = self.key_info_store.get_key_id(&key_triple)?;
+        let key_id = self.get_key_id(&key_triple)?;
+        let _key_use = self.key_users.acquire(key_id)?;
         let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
 
         op.validate(key_attributes)?;
+        self.check_hash_allowed(op.alg)?;
 
         let mech = Mechanism::try_from(Algorithm::from(op.alg)).map_err(to_response_status)?;
 
-        let session = self.new_session()?;
+        let session = self.new_read_only_session()?;
 
         let key = self.find_key(&session, key_id, KeyPairType::PrivateKey)?;
         info!("Located signing key.");
+        self.check_key_attributes(&session, key, key_attributes)?;
 
+        if let Some(params) = utils::rsa_pss_params(op.alg)? {
+            trace!("RSA-PSS parameters: {:?}", params);
+        }
+        let input = utils::signature_input(op.alg, op.hash.to_vec())?;
+        self.signed_hashes.record(key_id, &op.hash)?;
         trace!("Sign* command");
-        Ok(psa_sign_hash::Result {
-            signature: session
-                .sign(&mech, key, &utils::digest_info(op.alg, op.hash.to_vec())?)
-                .map_err(to_response_status)?
-                .into(),
-        })
+        match self.with_session_retry(&session, false, |session| {
+            self.with_relogin(session, || session.sign(&mech, key, &input))
+        }) {
+            Ok(signature) => Ok(psa_sign_hash::Result {
+                signature: signature.into(),
+            }),
+            Err(e) => {
+                self.signed_hashes.forget(key_id, &op.hash);
+                Err(e)
+            }
+        }
     }
 
     pub(super) fn psa_verify_hash_internal(
@@ -47,27 +71,36 @@ impl Provider {
         op: psa_verify_hash::Operation,
     ) -> Result<psa_verify_hash::Result> {
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, op.key_name.clone());
-        let key_id = self.key_info_store.get_key_id(&key_triple)?;
+        let key_id = self.get_key_id(&key_triple)?;
+        let _key_use = self.key_users.acquire(key_id)?;
         let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
 
         op.validate(key_attributes)?;
+        self.check_hash_allowed(op.alg)?;
+
+        let verified = VerifiedSignature::new(key_id, op.alg, &op.hash, &op.signature);
+        if self.verify_cache.contains(&verified) {
+            trace!("Signature found in the verify cache.");
+            return Ok(psa_verify_hash::Result {});
+        }
 
         let mech = Mechanism::try_from(Algorithm::from(op.alg)).map_err(to_response_status)?;
 
-        let session = self.new_session()?;
+        let session = self.new_read_only_session()?;
 
         let key = self.find_key(&session, key_id, KeyPairType::PublicKey)?;
         info!("Located public key.");
+        self.check_key_attributes(&session, key, key_attributes)?;
 
+        if let Some(params) = utils::rsa_pss_params(op.alg)? {
+            trace!("RSA-PSS parameters: {:?}", params);
+        }
         trace!("Verify* command");
-        session
-            .verify(
-                &mech,
-                key,
-                &utils::digest_info(op.alg, op.hash.to_vec())?,
-                &op.signature,
-            )
-            .map_err(to_response_status)?;
+        let input = utils::signature_input(op.alg, op.hash.to_vec())?;
+        self.with_session_retry(&session, false, |session| {
+            session.verify(&mech, key, &input, &op.signature)
+        })?;
+        self.verify_cache.insert(verified);
         Ok(psa_verify_hash::Result {})
     }
 
@@ -80,6 +113,7 @@ impl Provider {
         let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
 
         op.validate(key_attributes)?;
+        self.check_hash_allowed(op.alg)?;
 
         let pub_key_id = self.move_pub_key_to_psa_crypto(&key_triple)?;
 
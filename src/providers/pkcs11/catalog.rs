@@ -0,0 +1,502 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::warnings::{OperationWarning, Warnings};
+use super::{key_id, KeyId, KeyPairType, LocalIdStore, Provider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::{KeyInfo, KeyInfoManagerClient, KeyTriple};
+use log::{error, info};
+use parsec_interface::operations::psa_key_attributes::{Attributes, Type};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+/// Mapping of one key of the catalog: who owns it, its ID on the token and its attributes.
+/// No key material is part of the catalog.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    /// Name of the application owning the key
+    pub app_name: String,
+    /// Name of the key
+    pub key_name: String,
+    /// ID of the key objects on the token
+    pub key_id: KeyId,
+    /// Attributes of the key
+    pub attributes: Attributes,
+}
+
+impl Provider {
+    /// Export the mappings of all the keys of this provider, to import them on another Parsec
+    /// host using the same token.
+    pub fn export_catalog(&self) -> Result<Vec<CatalogEntry>> {
+        let mut catalog = Vec::new();
+        for key_triple in self.key_info_store.get_all()? {
+            catalog.push(CatalogEntry {
+                app_name: key_triple.app_name().to_string(),
+                key_name: key_triple.key_name().to_string(),
+                key_id: self.get_key_id(&key_triple)?,
+                attributes: self.key_info_store.get_key_attributes(&key_triple)?,
+            });
+        }
+
+        Ok(catalog)
+    }
+
+    /// Import the mappings exported from another Parsec host using the same token.
+    ///
+    /// The whole catalog is checked before anything is written: the keys must exist on the
+    /// token, have IDs of the configured width and not use an ID already used by another mapping.
+    /// Existing mappings with the same application and key names are overwritten. If storing one
+    /// of the mappings fails, the ones already written are rolled back.
+    pub fn import_catalog(&self, catalog: &[CatalogEntry]) -> Result<()> {
+        self.import_catalog_with_warnings(catalog, &mut Warnings::default())
+    }
+
+    /// Same as `import_catalog` but also reports the overwritten mappings in `warnings`.
+    pub fn import_catalog_with_warnings(
+        &self,
+        catalog: &[CatalogEntry],
+        warnings: &mut Warnings,
+    ) -> Result<()> {
+        let session = self.new_session()?;
+        import_entries(
+            &self.key_info_store,
+            &self.local_ids,
+            &self.unmapped_ids,
+            self.key_id_width,
+            catalog,
+            warnings,
+            |key_id, key_type| self.find_key(&session, key_id, key_type).map(|_| ()),
+        )
+    }
+}
+
+/// Object which must be on the token for a key of the given attributes.
+fn expected_object(attributes: &Attributes) -> KeyPairType {
+    match attributes.key_type {
+        Type::RsaKeyPair => KeyPairType::PrivateKey,
+        Type::RsaPublicKey => KeyPairType::PublicKey,
+        _ => KeyPairType::Any,
+    }
+}
+
+fn entry_key_triple(entry: &CatalogEntry) -> KeyTriple {
+    KeyTriple::new(
+        ApplicationName::from_name(entry.app_name.clone()),
+        ProviderID::Pkcs11,
+        entry.key_name.clone(),
+    )
+}
+
+fn log_refused_entry(entry: &CatalogEntry, reason: &str) {
+    if crate::utils::GlobalConfig::log_error_details() {
+        error!(
+            "Catalog key \"{}\" of \"{}\" refused: {}.",
+            entry.key_name, entry.app_name, reason
+        );
+    } else {
+        error!("Catalog key refused: {}.", reason);
+    }
+}
+
+/// Check and import the entries of a catalog. `find_on_token` checks that the object of a key
+/// is on the token. The `unmapped_ids` of key objects on the token which no mapping references
+/// can be imported.
+fn import_entries<F>(
+    key_info_store: &KeyInfoManagerClient,
+    local_ids: &RwLock<LocalIdStore>,
+    unmapped_ids: &RwLock<HashSet<KeyId>>,
+    key_id_width: usize,
+    catalog: &[CatalogEntry],
+    warnings: &mut Warnings,
+    find_on_token: F,
+) -> Result<()>
+where
+    F: Fn(KeyId, KeyPairType) -> Result<()>,
+{
+    let mut catalog_triples = HashSet::new();
+    let mut catalog_ids = HashSet::new();
+    for entry in catalog {
+        if entry.key_id.width() != key_id_width {
+            log_refused_entry(entry, "key ID of another width than the configured one");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if !catalog_triples.insert(entry_key_triple(entry)) || !catalog_ids.insert(entry.key_id) {
+            log_refused_entry(entry, "key or key ID found twice in the catalog");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if let Err(e) = find_on_token(entry.key_id, expected_object(&entry.attributes)) {
+            log_refused_entry(entry, "key not found in the PKCS 11 library");
+            return Err(e);
+        }
+    }
+
+    // The local IDs are locked from the checks until the mappings are written so that no new key
+    // takes one of the imported IDs in between.
+    let mut local_ids_handle = local_ids.write().expect("Local ID lock poisoned");
+    let stored_keys = key_id::stored_key_ids(key_info_store, &key_info_store.get_all()?);
+    let stored_ids: HashMap<KeyId, KeyTriple> = stored_keys
+        .iter()
+        .map(|(key_triple, key_id)| (*key_id, key_triple.clone()))
+        .collect();
+    for entry in catalog {
+        match stored_ids.get(&entry.key_id) {
+            Some(key_triple) if *key_triple != entry_key_triple(entry) => {
+                log_refused_entry(entry, "key ID already used by another key");
+                return Err(ResponseStatus::PsaErrorAlreadyExists);
+            }
+            Some(_) => (),
+            // The ID is reserved for a key being created.
+            None if local_ids_handle.contains(&entry.key_id) => {
+                log_refused_entry(entry, "key ID already used by another key");
+                return Err(ResponseStatus::PsaErrorAlreadyExists);
+            }
+            None => (),
+        }
+    }
+
+    let mut written = Vec::new();
+    for entry in catalog {
+        let key_triple = entry_key_triple(entry);
+        match insert_mapping(key_info_store, key_triple.clone(), entry) {
+            Ok(old_key_info) => written.push((key_triple, old_key_info)),
+            Err(e) => {
+                format_error!("Failed to store the catalog mappings, rolling back", e);
+                roll_back(key_info_store, written);
+                return Err(e);
+            }
+        }
+    }
+
+    // The IDs of the overwritten mappings are freed unless still used. Their objects stay on the
+    // token.
+    let mut unmapped_ids_handle = unmapped_ids.write().expect("Unmapped ID lock poisoned");
+    let used_ids: HashSet<KeyId> = stored_keys
+        .iter()
+        .filter(|(key_triple, _)| !catalog_triples.contains(key_triple))
+        .map(|(_, key_id)| *key_id)
+        .chain(catalog_ids.iter().copied())
+        .collect();
+    for (key_triple, key_id) in &stored_keys {
+        if catalog_triples.contains(key_triple) && !used_ids.contains(key_id) {
+            let _ = local_ids_handle.remove(key_id);
+            let _ = unmapped_ids_handle.insert(*key_id);
+        }
+    }
+    for key_id in &catalog_ids {
+        let _ = unmapped_ids_handle.remove(key_id);
+    }
+    local_ids_handle.extend(catalog_ids);
+
+    for (key_triple, old_key_info) in &written {
+        if old_key_info.is_some() {
+            warnings.push(OperationWarning::MappingOverwritten {
+                app_name: key_triple.app_name().to_string(),
+                key_name: key_triple.key_name().to_string(),
+            });
+        }
+    }
+    info!("Imported {} keys from the catalog.", catalog.len());
+
+    Ok(())
+}
+
+/// Insert the mapping of a catalog entry, replacing the existing one if any. Returns the replaced
+/// key info.
+fn insert_mapping(
+    key_info_store: &KeyInfoManagerClient,
+    key_triple: KeyTriple,
+    entry: &CatalogEntry,
+) -> Result<Option<KeyInfo>> {
+    let old_key_info = match key_info_store.remove_key_info(&key_triple) {
+        Ok(key_info) => Some(key_info),
+        Err(ResponseStatus::PsaErrorDoesNotExist) => None,
+        Err(e) => return Err(e),
+    };
+    if let Err(e) = key_id::insert_key_id(
+        key_info_store,
+        key_triple.clone(),
+        entry.key_id,
+        entry.attributes,
+    ) {
+        roll_back(key_info_store, vec![(key_triple, old_key_info)]);
+        return Err(e);
+    }
+
+    Ok(old_key_info)
+}
+
+/// Put back the mappings as they were before they were written.
+fn roll_back(key_info_store: &KeyInfoManagerClient, written: Vec<(KeyTriple, Option<KeyInfo>)>) {
+    for (key_triple, old_key_info) in written.into_iter().rev() {
+        let rolled_back = match old_key_info {
+            Some(key_info) => key_info_store.restore_key_info(key_triple.clone(), key_info),
+            None => match key_info_store.remove_key_info(&key_triple) {
+                Ok(_) | Err(ResponseStatus::PsaErrorDoesNotExist) => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = rolled_back {
+            format_error!(
+                format!("Failed to roll back the mapping of {}", key_triple),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{import_entries, CatalogEntry, KeyId, KeyPairType, LocalIdStore};
+    use crate::key_info_managers::test_utils::{test_client, test_key_attributes};
+    use crate::key_info_managers::KeyInfoManagerClient;
+    use crate::providers::pkcs11::key_id;
+    use crate::providers::pkcs11::warnings::{OperationWarning, Warnings};
+    use parsec_interface::requests::{ResponseStatus, Result};
+    use std::collections::HashSet;
+    use std::fs;
+    use std::sync::RwLock;
+
+    fn test_entry(key_name: &str, key_id: KeyId) -> CatalogEntry {
+        CatalogEntry {
+            app_name: "app".to_string(),
+            key_name: key_name.to_string(),
+            key_id,
+            attributes: test_key_attributes(),
+        }
+    }
+
+    /// Import a catalog against a token holding the given objects.
+    fn import(
+        client: &KeyInfoManagerClient,
+        local_ids: &RwLock<LocalIdStore>,
+        unmapped_ids: &RwLock<HashSet<KeyId>>,
+        token: &[(KeyId, KeyPairType)],
+        catalog: &[CatalogEntry],
+        warnings: &mut Warnings,
+    ) -> Result<()> {
+        import_entries(
+            client,
+            local_ids,
+            unmapped_ids,
+            4,
+            catalog,
+            warnings,
+            |key_id, key_type| {
+                if token.iter().any(|(id, object_type)| {
+                    *id == key_id && (*object_type == key_type || key_type == KeyPairType::Any)
+                }) {
+                    Ok(())
+                } else {
+                    Err(ResponseStatus::PsaErrorDoesNotExist)
+                }
+            },
+        )
+    }
+
+    fn key_pair(key_id: u32) -> [(KeyId, KeyPairType); 2] {
+        [
+            (KeyId::from(key_id), KeyPairType::PublicKey),
+            (KeyId::from(key_id), KeyPairType::PrivateKey),
+        ]
+    }
+
+    #[test]
+    fn catalog_round_trip() {
+        let catalog = vec![
+            test_entry("key", KeyId::from(0x1234_5678)),
+            test_entry("key", KeyId::from_bytes(&[0x12; 16]).unwrap()),
+        ];
+
+        let manifest = bincode::serialize(&catalog).unwrap();
+        let imported: Vec<CatalogEntry> = bincode::deserialize(&manifest).unwrap();
+
+        assert_eq!(imported, catalog);
+    }
+
+    #[test]
+    fn overwritten_mapping_is_reported() {
+        let (client, path) = test_client("overwritten_mapping");
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let unmapped_ids = RwLock::new(HashSet::new());
+        let mut token = key_pair(1).to_vec();
+        token.extend_from_slice(&key_pair(2));
+
+        let mut warnings = Warnings::collecting();
+        import(
+            &client,
+            &local_ids,
+            &unmapped_ids,
+            &token,
+            &[test_entry("key", KeyId::from(1))],
+            &mut warnings,
+        )
+        .unwrap();
+        import(
+            &client,
+            &local_ids,
+            &unmapped_ids,
+            &token,
+            &[test_entry("key", KeyId::from(2))],
+            &mut warnings,
+        )
+        .unwrap();
+
+        assert_eq!(
+            warnings.into_vec(),
+            vec![OperationWarning::MappingOverwritten {
+                app_name: "app".to_string(),
+                key_name: "key".to_string(),
+            }]
+        );
+        let key_triple = client.get_all().unwrap().pop().unwrap();
+        assert_eq!(
+            key_id::stored_key_id(&client, &key_triple).unwrap(),
+            KeyId::from(2)
+        );
+        // The ID of the overwritten mapping is not used anymore but its objects are still on the
+        // token.
+        assert_eq!(
+            *local_ids.read().unwrap(),
+            [KeyId::from(2)].iter().copied().collect::<HashSet<_>>()
+        );
+        assert_eq!(
+            *unmapped_ids.read().unwrap(),
+            [KeyId::from(1)].iter().copied().collect::<HashSet<_>>()
+        );
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn unmapped_key_id_is_imported() {
+        let (client, path) = test_client("catalog_unmapped_key_id");
+        let local_ids = RwLock::new(LocalIdStore::new());
+        // Found on the token at startup, without a mapping.
+        let unmapped_ids = RwLock::new([KeyId::from(1)].iter().copied().collect());
+        let token = key_pair(1);
+
+        import(
+            &client,
+            &local_ids,
+            &unmapped_ids,
+            &token,
+            &[test_entry("key", KeyId::from(1))],
+            &mut Warnings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(client.get_all().unwrap().len(), 1);
+        assert_eq!(
+            *local_ids.read().unwrap(),
+            [KeyId::from(1)].iter().copied().collect::<HashSet<_>>()
+        );
+        assert!(unmapped_ids.read().unwrap().is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn key_pair_without_private_object_is_refused() {
+        let (client, path) = test_client("key_pair_without_private_object");
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let unmapped_ids = RwLock::new(HashSet::new());
+        let mut token = key_pair(1).to_vec();
+        token.push((KeyId::from(2), KeyPairType::PublicKey));
+
+        let catalog = [
+            test_entry("key one", KeyId::from(1)),
+            test_entry("key two", KeyId::from(2)),
+        ];
+        assert_eq!(
+            import(
+                &client,
+                &local_ids,
+                &unmapped_ids,
+                &token,
+                &catalog,
+                &mut Warnings::default()
+            ),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+        // Nothing was imported.
+        assert!(client.get_all().unwrap().is_empty());
+        assert!(local_ids.read().unwrap().is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn key_id_of_another_width_is_refused() {
+        let (client, path) = test_client("catalog_key_id_width");
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let unmapped_ids = RwLock::new(HashSet::new());
+        let key_id = KeyId::from_bytes(&[0x12; 8]).unwrap();
+        let token = [
+            (key_id, KeyPairType::PublicKey),
+            (key_id, KeyPairType::PrivateKey),
+        ];
+
+        assert_eq!(
+            import(
+                &client,
+                &local_ids,
+                &unmapped_ids,
+                &token,
+                &[test_entry("key", key_id)],
+                &mut Warnings::default()
+            ),
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        );
+        assert!(client.get_all().unwrap().is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn key_id_of_another_key_is_refused() {
+        let (client, path) = test_client("catalog_key_id_of_another_key");
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let unmapped_ids = RwLock::new(HashSet::new());
+        let mut token = key_pair(1).to_vec();
+        token.extend_from_slice(&key_pair(2));
+
+        import(
+            &client,
+            &local_ids,
+            &unmapped_ids,
+            &token,
+            &[test_entry("key one", KeyId::from(1))],
+            &mut Warnings::default(),
+        )
+        .unwrap();
+        // Used by a stored key.
+        assert_eq!(
+            import(
+                &client,
+                &local_ids,
+                &unmapped_ids,
+                &token,
+                &[test_entry("key two", KeyId::from(1))],
+                &mut Warnings::default()
+            ),
+            Err(ResponseStatus::PsaErrorAlreadyExists)
+        );
+        // Reserved for a key being created.
+        let _ = local_ids.write().unwrap().insert(KeyId::from(2));
+        assert_eq!(
+            import(
+                &client,
+                &local_ids,
+                &unmapped_ids,
+                &token,
+                &[test_entry("key two", KeyId::from(2))],
+                &mut Warnings::default()
+            ),
+            Err(ResponseStatus::PsaErrorAlreadyExists)
+        );
+        assert_eq!(client.get_all().unwrap().len(), 1);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
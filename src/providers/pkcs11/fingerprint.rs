@@ -0,0 +1,144 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::{KeyId, Provider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::error;
+use parsec_interface::operations::psa_algorithm::Hash;
+use parsec_interface::operations::psa_export_public_key;
+use parsec_interface::operations::psa_key_attributes::Type;
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use picky_asn1_x509::{RSAPublicKey, SubjectPublicKeyInfo};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Fingerprints already computed, by key ID.
+#[derive(Debug, Default)]
+pub(super) struct FingerprintCache {
+    fingerprints: RwLock<HashMap<KeyId, Vec<u8>>>,
+}
+
+impl FingerprintCache {
+    fn get(&self, key_id: KeyId) -> Option<Vec<u8>> {
+        self.fingerprints
+            .read()
+            .expect("Fingerprint cache lock poisoned")
+            .get(&key_id)
+            .cloned()
+    }
+
+    fn insert(&self, key_id: KeyId, fingerprint: Vec<u8>) {
+        let _ = self
+            .fingerprints
+            .write()
+            .expect("Fingerprint cache lock poisoned")
+            .insert(key_id, fingerprint);
+    }
+
+    /// Forget the fingerprint of a key, when it is destroyed.
+    pub(super) fn invalidate_key(&self, key_id: KeyId) {
+        let _ = self
+            .fingerprints
+            .write()
+            .expect("Fingerprint cache lock poisoned")
+            .remove(&key_id);
+    }
+}
+
+impl Provider {
+    /// Get the fingerprint of a key: the SHA-256 of the DER-encoded SubjectPublicKeyInfo of its
+    /// public part.
+    ///
+    /// The fingerprint does not reveal anything secret and is the same for the same key on other
+    /// systems. Keys without a public part fail with `PsaErrorNotSupported`.
+    pub fn key_fingerprint(&self, app_name: ApplicationName, key_name: String) -> Result<Vec<u8>> {
+        let key_triple = KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key_name.clone());
+        let key_id = self.get_key_id(&key_triple)?;
+        let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
+        match key_attributes.key_type {
+            Type::RsaKeyPair | Type::RsaPublicKey => (),
+            _ => return Err(ResponseStatus::PsaErrorNotSupported),
+        }
+
+        if let Some(fingerprint) = self.fingerprint_cache.get(key_id) {
+            return Ok(fingerprint);
+        }
+        let psa_export_public_key::Result { data } = self.psa_export_public_key_internal(
+            app_name,
+            psa_export_public_key::Operation { key_name },
+        )?;
+        let fingerprint = rsa_fingerprint(&data)?;
+        self.fingerprint_cache.insert(key_id, fingerprint.clone());
+
+        Ok(fingerprint)
+    }
+}
+
+/// Compute the fingerprint of a DER-encoded `RSAPublicKey`.
+fn rsa_fingerprint(public_key: &[u8]) -> Result<Vec<u8>> {
+    let key: RSAPublicKey = picky_asn1_der::from_bytes(public_key).map_err(|err| {
+        format_error!("Could not deserialise key elements", err);
+        ResponseStatus::PsaErrorGenericError
+    })?;
+    let spki = picky_asn1_der::to_vec(&SubjectPublicKeyInfo::new_rsa_key(
+        key.modulus,
+        key.public_exponent,
+    ))
+    .map_err(|err| {
+        format_error!("Could not serialise the SubjectPublicKeyInfo", err);
+        ResponseStatus::PsaErrorGenericError
+    })?;
+
+    psa_crypto::init()?;
+    let mut fingerprint = vec![0; Hash::Sha256.hash_length()];
+    let _ = psa_crypto::operations::hash::hash_compute(Hash::Sha256, &spki, &mut fingerprint)
+        .map_err(|err| {
+            error!("Could not hash the SubjectPublicKeyInfo.");
+            err
+        })?;
+    Ok(fingerprint)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rsa_fingerprint, FingerprintCache};
+    use crate::providers::pkcs11::KeyId;
+
+    // RSAPublicKey of a 1024 bits RSA key.
+    const PUBLIC_KEY: [u8; 140] = [
+        0x30, 0x81, 0x89, 0x02, 0x81, 0x81, 0x00, 0xB9, 0x10, 0xDA, 0x34, 0xAB, 0x9B, 0x41, 0xB5,
+        0xF4, 0x1C, 0x9B, 0x2D, 0xBF, 0x2B, 0x0D, 0x16, 0xF7, 0xC5, 0xFE, 0x33, 0x13, 0x74, 0xF3,
+        0x8A, 0xB8, 0x1E, 0x8C, 0x5E, 0x70, 0x4E, 0x3A, 0xF6, 0xAF, 0xF0, 0xDA, 0x89, 0x0B, 0x6C,
+        0x2D, 0xE5, 0x32, 0x58, 0xC9, 0xE4, 0x87, 0x27, 0xB6, 0x1A, 0x16, 0xB1, 0x7E, 0x2F, 0xB5,
+        0x42, 0x82, 0xF8, 0x93, 0xB3, 0xF0, 0x7D, 0xC0, 0x63, 0x99, 0x06, 0x72, 0x09, 0xF6, 0x50,
+        0xCE, 0xBA, 0xC2, 0xD9, 0x09, 0x6A, 0x1F, 0xFD, 0x63, 0x20, 0x71, 0x31, 0x22, 0xAC, 0xBD,
+        0x9B, 0x79, 0xEE, 0xA5, 0x70, 0x5F, 0x17, 0xC6, 0x28, 0x85, 0x9D, 0x15, 0xDC, 0x91, 0xF0,
+        0x10, 0x81, 0xFB, 0x0B, 0x65, 0x53, 0xF2, 0x0A, 0xF7, 0x69, 0xCF, 0xEE, 0xE6, 0x95, 0x7A,
+        0xA3, 0x01, 0x12, 0x89, 0x73, 0x9C, 0xC6, 0xD9, 0xF8, 0xFF, 0x26, 0xEA, 0xB9, 0x43, 0xF3,
+        0x02, 0x03, 0x01, 0x00, 0x01,
+    ];
+
+    // `openssl rsa -pubout -outform DER | sha256sum` of the same key.
+    const FINGERPRINT: [u8; 32] = [
+        0x58, 0xBB, 0x84, 0xD1, 0x2B, 0x71, 0xC8, 0x7B, 0x3D, 0xBA, 0xFC, 0x34, 0xBE, 0xAA, 0xD1,
+        0xA1, 0xFB, 0xAC, 0x08, 0x0C, 0xF9, 0x8E, 0x6A, 0xC0, 0x81, 0x90, 0xA9, 0xA8, 0x04, 0xA8,
+        0x19, 0x48,
+    ];
+
+    #[test]
+    fn fingerprint_is_sha256_of_spki() {
+        assert_eq!(rsa_fingerprint(&PUBLIC_KEY).unwrap(), FINGERPRINT.to_vec());
+        assert_eq!(rsa_fingerprint(&PUBLIC_KEY).unwrap(), FINGERPRINT.to_vec());
+    }
+
+    #[test]
+    fn destroyed_key_fingerprint_is_forgotten() {
+        let cache = FingerprintCache::default();
+        cache.insert(KeyId::from(1), FINGERPRINT.to_vec());
+        cache.insert(KeyId::from(2), FINGERPRINT.to_vec());
+        cache.invalidate_key(KeyId::from(1));
+
+        assert_eq!(cache.get(KeyId::from(1)), None);
+        assert_eq!(cache.get(KeyId::from(2)), Some(FINGERPRINT.to_vec()));
+    }
+}
@@ -0,0 +1,144 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::utils::KeyPairType;
+use super::KeyId;
+use parsec_interface::requests::Result;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Cache of the object handles found for each key ID and key pair type, to avoid searching the
+/// token for the same objects on every operation.
+///
+/// A cached handle is only used once checked to still refer to the key: handles of objects
+/// destroyed or changed outside of Parsec are evicted and looked up again.
+#[derive(Debug)]
+pub(super) struct HandleCache<H> {
+    handles: RwLock<HashMap<(KeyId, KeyPairType), H>>,
+}
+
+impl<H> Default for HandleCache<H> {
+    fn default() -> Self {
+        HandleCache {
+            handles: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<H: Copy> HandleCache<H> {
+    /// Get the handle of a key, from the cache if `check` accepts the cached one or from `find`
+    /// otherwise. The handle found is cached.
+    pub(super) fn lookup(
+        &self,
+        key_id: KeyId,
+        key_type: KeyPairType,
+        check: impl FnOnce(H) -> bool,
+        find: impl FnOnce() -> Result<H>,
+    ) -> Result<H> {
+        let cached = self
+            .handles
+            .read()
+            .expect("Handle cache lock poisoned")
+            .get(&(key_id, key_type))
+            .copied();
+        if let Some(handle) = cached {
+            if check(handle) {
+                return Ok(handle);
+            }
+            let _ = self
+                .handles
+                .write()
+                .expect("Handle cache lock poisoned")
+                .remove(&(key_id, key_type));
+        }
+
+        let handle = find()?;
+        let _ = self
+            .handles
+            .write()
+            .expect("Handle cache lock poisoned")
+            .insert((key_id, key_type), handle);
+        Ok(handle)
+    }
+
+    /// Forget the handles of a key, when it is destroyed.
+    pub(super) fn invalidate_key(&self, key_id: KeyId) {
+        self.handles
+            .write()
+            .expect("Handle cache lock poisoned")
+            .retain(|(cached_id, _), _| *cached_id != key_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HandleCache;
+    use crate::providers::pkcs11::utils::KeyPairType;
+    use crate::providers::pkcs11::KeyId;
+    use parsec_interface::requests::ResponseStatus;
+    use std::cell::Cell;
+
+    #[test]
+    fn second_lookup_hits_cache() {
+        let cache = HandleCache::default();
+        let find_objects_calls = Cell::new(0);
+        let find = || {
+            find_objects_calls.set(find_objects_calls.get() + 1);
+            Ok(42u64)
+        };
+
+        let first = cache.lookup(KeyId::from(1), KeyPairType::PublicKey, |_| true, find);
+        let second = cache.lookup(KeyId::from(1), KeyPairType::PublicKey, |_| true, find);
+
+        assert_eq!(first.unwrap(), 42);
+        assert_eq!(second.unwrap(), 42);
+        assert_eq!(find_objects_calls.get(), 1);
+
+        // The other half of the key pair is not cached yet.
+        let _ = cache.lookup(KeyId::from(1), KeyPairType::PrivateKey, |_| true, find);
+        assert_eq!(find_objects_calls.get(), 2);
+    }
+
+    #[test]
+    fn stale_handle_is_looked_up_again() {
+        let cache = HandleCache::default();
+        let _ = cache.lookup(KeyId::from(1), KeyPairType::Any, |_| true, || Ok(42u64));
+
+        let handle = cache.lookup(
+            KeyId::from(1),
+            KeyPairType::Any,
+            |handle| handle != 42,
+            || Ok(43),
+        );
+        assert_eq!(handle.unwrap(), 43);
+        let handle = cache.lookup(KeyId::from(1), KeyPairType::Any, |_| true, || Ok(44));
+        assert_eq!(handle.unwrap(), 43);
+    }
+
+    #[test]
+    fn destroyed_key_is_not_cached() {
+        let cache = HandleCache::default();
+        let _ = cache.lookup(
+            KeyId::from(1),
+            KeyPairType::PublicKey,
+            |_| true,
+            || Ok(42u64),
+        );
+        let _ = cache.lookup(
+            KeyId::from(2),
+            KeyPairType::PublicKey,
+            |_| true,
+            || Ok(43u64),
+        );
+        cache.invalidate_key(KeyId::from(1));
+
+        let handle = cache.lookup(
+            KeyId::from(1),
+            KeyPairType::PublicKey,
+            |_| true,
+            || Err(ResponseStatus::PsaErrorDoesNotExist),
+        );
+        assert_eq!(handle.unwrap_err(), ResponseStatus::PsaErrorDoesNotExist);
+        let handle = cache.lookup(KeyId::from(2), KeyPairType::PublicKey, |_| true, || Ok(44));
+        assert_eq!(handle.unwrap(), 43);
+    }
+}
@@ -0,0 +1,341 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::utils::{self, to_response_status};
+use super::{KeyPairType, Provider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use cryptoki::types::mechanism::Mechanism;
+use cryptoki::types::session::Session;
+use log::{error, info, trace, warn};
+use parsec_interface::operations::psa_algorithm::{
+    Algorithm, AsymmetricEncryption, AsymmetricSignature, Hash, SignHash,
+};
+use parsec_interface::operations::psa_key_attributes::{Attributes, Type};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use std::convert::TryFrom;
+
+/// Outcome of the functional check of one key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyHealth {
+    /// Name of the key
+    pub key_name: String,
+    /// Error returned by the check, `None` if the key is functional
+    pub error: Option<ResponseStatus>,
+}
+
+/// Functional check done on a key, chosen from its type and policy.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum KeyCheck {
+    /// Sign a dummy hash with the private key and verify the signature with the public one.
+    SignVerify(AsymmetricSignature),
+    /// Encrypt a random message with the public key and decrypt it with the private one.
+    EncryptDecrypt(AsymmetricEncryption),
+    /// Find both objects of a key pair whose policy permits no other check.
+    FindKeyPair,
+    /// Export the public key.
+    ExportPublic,
+}
+
+impl Provider {
+    /// Check that all the keys of an application are still usable, using `parallelism` sessions
+    /// at most.
+    ///
+    /// Key pairs permitted to sign hashes sign and verify a dummy hash, the ones permitted to
+    /// decrypt encrypt and decrypt a random message and public keys are exported. When only the
+    /// operations using public keys are allowed, the public part of the key pairs is exported
+    /// instead. Nothing is modified on the token.
+    pub fn check_keys_health(
+        &self,
+        app_name: ApplicationName,
+        parallelism: usize,
+    ) -> Result<Vec<KeyHealth>> {
+        let key_names: Vec<String> = self
+            .key_info_store
+            .list_keys(&app_name)?
+            .into_iter()
+            .map(|key_info| key_info.name)
+            .collect();
+        let private_operations = self.check_private_operations_allowed().is_ok();
+
+        let report = sweep(
+            &key_names,
+            parallelism,
+            || self.new_read_only_session(),
+            |session, key_name| self.check_key(session, &app_name, key_name, private_operations),
+        )?;
+        info!(
+            "{} keys checked, {} not functional.",
+            report.len(),
+            report.iter().filter(|key| key.error.is_some()).count()
+        );
+
+        Ok(report)
+    }
+
+    fn check_key(
+        &self,
+        session: &Session,
+        app_name: &ApplicationName,
+        key_name: &str,
+        private_operations: bool,
+    ) -> Result<()> {
+        let key_triple = KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key_name.to_string());
+        let key_id = self.get_key_id(&key_triple)?;
+        let _key_use = self.key_users.acquire(key_id)?;
+        let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
+
+        let check = key_check(&key_attributes, private_operations);
+        if check == KeyCheck::ExportPublic {
+            let _ = self.export_public_key_data(session, &key_triple, key_id)?;
+            return Ok(());
+        }
+
+        let public_key = self.find_key(session, key_id, KeyPairType::PublicKey)?;
+        let private_key = self.find_key(session, key_id, KeyPairType::PrivateKey)?;
+        match check {
+            KeyCheck::SignVerify(alg) => {
+                let hash_len = match alg.hash() {
+                    Some(SignHash::Specific(hash)) => hash.hash_length(),
+                    _ => return Err(ResponseStatus::PsaErrorNotSupported),
+                };
+                let mech = Mechanism::try_from(Algorithm::from(alg)).map_err(to_response_status)?;
+                let input = utils::signature_input(alg, vec![0; hash_len])?;
+
+                trace!("Sign command");
+                let signature = session
+                    .sign(&mech, private_key, &input)
+                    .map_err(to_response_status)?;
+                trace!("Verify command");
+                session
+                    .verify(&mech, public_key, &input, &signature)
+                    .map_err(to_response_status)?;
+            }
+            KeyCheck::EncryptDecrypt(alg) => {
+                let mech = Mechanism::try_from(Algorithm::from(alg)).map_err(to_response_status)?;
+                let message = rand::random::<[u8; 16]>();
+
+                trace!("Encrypt command");
+                let ciphertext = session
+                    .encrypt(&mech, public_key, &message)
+                    .map_err(to_response_status)?;
+                trace!("Decrypt command");
+                let plaintext = session
+                    .decrypt(&mech, private_key, &ciphertext)
+                    .map_err(to_response_status)?;
+                if plaintext != message {
+                    error!("Decrypted message does not match the encrypted one.");
+                    return Err(ResponseStatus::PsaErrorCorruptionDetected);
+                }
+            }
+            // Both halves were found, there is nothing more to check.
+            KeyCheck::FindKeyPair | KeyCheck::ExportPublic => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Choose the check of a key from its type and policy. Only the public part of the keys is
+/// checked without `private_operations`.
+fn key_check(attributes: &Attributes, private_operations: bool) -> KeyCheck {
+    if attributes.key_type != Type::RsaKeyPair || !private_operations {
+        return KeyCheck::ExportPublic;
+    }
+    let usage_flags = attributes.policy.usage_flags;
+    match attributes.policy.permitted_algorithms {
+        Algorithm::AsymmetricSignature(alg) if usage_flags.sign_hash => {
+            KeyCheck::SignVerify(with_specific_hash(alg))
+        }
+        Algorithm::AsymmetricEncryption(alg) if usage_flags.decrypt => {
+            KeyCheck::EncryptDecrypt(alg)
+        }
+        _ => KeyCheck::FindKeyPair,
+    }
+}
+
+/// Replace a wildcard hash by SHA-256 so that the signature algorithm can be used.
+fn with_specific_hash(alg: AsymmetricSignature) -> AsymmetricSignature {
+    match alg {
+        AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: SignHash::Any,
+        } => AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: Hash::Sha256.into(),
+        },
+        AsymmetricSignature::RsaPss {
+            hash_alg: SignHash::Any,
+        } => AsymmetricSignature::RsaPss {
+            hash_alg: Hash::Sha256.into(),
+        },
+        alg => alg,
+    }
+}
+
+/// Run `check` on all the keys, on `parallelism` threads, and report which ones failed.
+fn sweep<S>(
+    key_names: &[String],
+    parallelism: usize,
+    open: impl Fn() -> Result<S> + Sync,
+    check: impl Fn(&S, &str) -> Result<()> + Sync,
+) -> Result<Vec<KeyHealth>> {
+    utils::parallel_with_sessions(key_names, parallelism, open, |session, key_name| {
+        let error = check(session, key_name).err();
+        if let Some(error) = error {
+            if crate::utils::GlobalConfig::log_error_details() {
+                warn!("Key {} is not functional: {}.", key_name, error);
+            } else {
+                warn!("Key is not functional.");
+            }
+        }
+        Ok(KeyHealth {
+            key_name: key_name.clone(),
+            error,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{key_check, sweep, with_specific_hash, KeyCheck, KeyHealth};
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricEncryption, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::ResponseStatus;
+
+    fn attributes(key_type: Type, usage_flags: UsageFlags, alg: Algorithm) -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type,
+            bits: 2048,
+            policy: Policy {
+                usage_flags,
+                permitted_algorithms: alg,
+            },
+        }
+    }
+
+    #[test]
+    fn check_follows_policy() {
+        let sign = AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: SignHash::Any,
+        };
+        let encrypt = AsymmetricEncryption::RsaPkcs1v15Crypt;
+        let sign_usage = UsageFlags {
+            sign_hash: true,
+            ..Default::default()
+        };
+        let decrypt_usage = UsageFlags {
+            decrypt: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            key_check(&attributes(Type::RsaKeyPair, sign_usage, sign.into()), true),
+            KeyCheck::SignVerify(with_specific_hash(sign))
+        );
+        assert_eq!(
+            key_check(
+                &attributes(Type::RsaKeyPair, decrypt_usage, encrypt.into()),
+                true
+            ),
+            KeyCheck::EncryptDecrypt(encrypt)
+        );
+        // The policy does not permit the private key to be used.
+        assert_eq!(
+            key_check(
+                &attributes(Type::RsaKeyPair, decrypt_usage, sign.into()),
+                true
+            ),
+            KeyCheck::FindKeyPair
+        );
+        assert_eq!(
+            key_check(
+                &attributes(Type::RsaPublicKey, sign_usage, sign.into()),
+                true
+            ),
+            KeyCheck::ExportPublic
+        );
+    }
+
+    #[test]
+    fn public_only_check() {
+        let sign = AsymmetricSignature::RsaPss {
+            hash_alg: Hash::Sha256.into(),
+        };
+        let sign_usage = UsageFlags {
+            sign_hash: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            key_check(
+                &attributes(Type::RsaKeyPair, sign_usage, sign.into()),
+                false
+            ),
+            KeyCheck::ExportPublic
+        );
+    }
+
+    #[test]
+    fn sweep_reports_broken_key() {
+        let key_names: Vec<String> = (0..10).map(|i| format!("key {}", i)).collect();
+
+        let report = sweep(
+            &key_names,
+            3,
+            || Ok(()),
+            |_, key_name| {
+                if key_name == "key 7" {
+                    Err(ResponseStatus::PsaErrorInvalidSignature)
+                } else {
+                    Ok(())
+                }
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.len(), key_names.len());
+        let broken: Vec<&KeyHealth> = report.iter().filter(|key| key.error.is_some()).collect();
+        assert_eq!(
+            broken,
+            vec![&KeyHealth {
+                key_name: "key 7".to_string(),
+                error: Some(ResponseStatus::PsaErrorInvalidSignature),
+            }]
+        );
+    }
+
+    #[test]
+    fn sweep_fails_without_session() {
+        let key_names = vec!["key".to_string()];
+
+        assert_eq!(
+            sweep(
+                &key_names,
+                1,
+                || Err::<(), _>(ResponseStatus::PsaErrorInsufficientMemory),
+                |_, _| Ok(()),
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientMemory
+        );
+    }
+
+    #[test]
+    fn wildcard_hash_replaced() {
+        assert_eq!(
+            with_specific_hash(AsymmetricSignature::RsaPss {
+                hash_alg: SignHash::Any
+            }),
+            AsymmetricSignature::RsaPss {
+                hash_alg: Hash::Sha256.into()
+            }
+        );
+        let alg = AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: Hash::Sha384.into(),
+        };
+        assert_eq!(with_specific_hash(alg), alg);
+    }
+}
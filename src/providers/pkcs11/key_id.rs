@@ -0,0 +1,273 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
+use log::error;
+use parsec_interface::operations::psa_key_attributes::Attributes;
+use parsec_interface::requests::{ResponseStatus, Result};
+use serde::ser::{SerializeTuple, Serializer};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Widths, in bytes, allowed for the IDs of keys.
+pub const KEY_ID_WIDTHS: [usize; 3] = [4, 8, 16];
+
+/// Width of the IDs of keys created before the width could be configured.
+pub const LEGACY_KEY_ID_WIDTH: usize = 4;
+
+/// ID of the objects of a key on the token (`CKA_ID`).
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "Vec<u8>", into = "Vec<u8>")]
+pub struct KeyId {
+    width: u8,
+    bytes: [u8; 16],
+}
+
+impl KeyId {
+    /// Create a key ID from the bytes of a `CKA_ID`. Returns `None` if the width is not one of
+    /// `KEY_ID_WIDTHS`.
+    pub fn from_bytes(id: &[u8]) -> Option<KeyId> {
+        if !KEY_ID_WIDTHS.contains(&id.len()) {
+            return None;
+        }
+        let mut bytes = [0; 16];
+        bytes[..id.len()].copy_from_slice(id);
+        Some(KeyId {
+            width: id.len() as u8,
+            bytes,
+        })
+    }
+
+    /// Create a random key ID of the given width. Returns `None` if the width is not one of
+    /// `KEY_ID_WIDTHS`.
+    pub fn random(width: usize) -> Option<KeyId> {
+        KeyId::from_bytes(rand::random::<[u8; 16]>().get(..width)?)
+    }
+
+    /// The bytes of the `CKA_ID`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.width()]
+    }
+
+    /// The width of the ID in bytes.
+    pub fn width(&self) -> usize {
+        usize::from(self.width)
+    }
+}
+
+impl From<u32> for KeyId {
+    fn from(key_id: u32) -> Self {
+        let mut bytes = [0; 16];
+        bytes[..LEGACY_KEY_ID_WIDTH].copy_from_slice(&key_id.to_be_bytes());
+        KeyId {
+            width: LEGACY_KEY_ID_WIDTH as u8,
+            bytes,
+        }
+    }
+}
+
+impl TryFrom<Vec<u8>> for KeyId {
+    type Error = String;
+
+    fn try_from(id: Vec<u8>) -> std::result::Result<Self, Self::Error> {
+        KeyId::from_bytes(&id).ok_or_else(|| format!("invalid key ID width {}", id.len()))
+    }
+}
+
+impl From<KeyId> for Vec<u8> {
+    fn from(key_id: KeyId) -> Self {
+        key_id.as_bytes().to_vec()
+    }
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Key ID as stored in the Key Info Manager.
+///
+/// 4 bytes IDs are stored as the `u32` they used to be, so that the entries written before the
+/// width could be configured are still read. Wider IDs are stored as their bytes.
+struct StoredKeyId(KeyId);
+
+impl Serialize for StoredKeyId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let key_id = self.0;
+        if key_id.width() == LEGACY_KEY_ID_WIDTH {
+            let mut legacy = [0; LEGACY_KEY_ID_WIDTH];
+            legacy.copy_from_slice(key_id.as_bytes());
+            return serializer.serialize_u32(u32::from_be_bytes(legacy));
+        }
+        let mut tuple = serializer.serialize_tuple(key_id.width())?;
+        for byte in key_id.as_bytes() {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+/// Decode a key ID stored in the Key Info Manager.
+///
+/// IDs of any of the `KEY_ID_WIDTHS` are accepted: the configured width only applies to the IDs
+/// of new keys, the keys created with another width stay usable.
+fn from_stored(stored: &[u8]) -> Result<KeyId> {
+    if stored.len() == LEGACY_KEY_ID_WIDTH {
+        return Ok(KeyId::from(bincode::deserialize::<u32>(stored)?));
+    }
+    KeyId::from_bytes(stored).ok_or_else(|| {
+        error!("Stored key ID of {} bytes.", stored.len());
+        ResponseStatus::InvalidEncoding
+    })
+}
+
+/// Get the ID of a key from the Key Info Manager.
+pub(super) fn stored_key_id(
+    key_info_store: &KeyInfoManagerClient,
+    key_triple: &KeyTriple,
+) -> Result<KeyId> {
+    from_stored(&key_info_store.get_key_info(key_triple)?.id)
+}
+
+/// Get the IDs of the given keys from the Key Info Manager.
+///
+/// Keys whose ID cannot be read are logged and left out. Their mapping is kept: it is not the
+/// provider's to delete because it failed to decode it.
+pub(super) fn stored_key_ids(
+    key_info_store: &KeyInfoManagerClient,
+    key_triples: &[KeyTriple],
+) -> Vec<(KeyTriple, KeyId)> {
+    let mut stored_keys = Vec::new();
+    for key_triple in key_triples {
+        match stored_key_id(key_info_store, key_triple) {
+            Ok(key_id) => stored_keys.push((key_triple.clone(), key_id)),
+            Err(ResponseStatus::PsaErrorDoesNotExist) => {
+                error!("Stored key info missing for key triple {}.", key_triple);
+            }
+            Err(e) => {
+                format_error!(
+                    format!(
+                        "Stored key info invalid for key triple {}, ignoring the key.",
+                        key_triple
+                    ),
+                    e
+                );
+            }
+        }
+    }
+    stored_keys
+}
+
+/// Store the mapping of a key to its ID in the Key Info Manager.
+pub(super) fn insert_key_id(
+    key_info_store: &KeyInfoManagerClient,
+    key_triple: KeyTriple,
+    key_id: KeyId,
+    attributes: Attributes,
+) -> Result<()> {
+    key_info_store.insert_key_info(key_triple, &StoredKeyId(key_id), attributes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_stored, insert_key_id, stored_key_id, stored_key_ids, KeyId};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::test_utils::{test_client, test_key_attributes};
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::fs;
+
+    /// Store key IDs with an on-disk Key Info Manager and read them back with the given width.
+    fn stored_and_read(name: &str, key_ids: &[KeyId]) -> Vec<KeyId> {
+        let (client, store_path) = test_client(name);
+
+        let mut read = Vec::new();
+        for (index, key_id) in key_ids.iter().enumerate() {
+            let key_triple = KeyTriple::new(
+                ApplicationName::from_name("app".to_string()),
+                ProviderID::Pkcs11,
+                format!("key {}", index),
+            );
+            insert_key_id(&client, key_triple.clone(), *key_id, test_key_attributes()).unwrap();
+            read.push(stored_key_id(&client, &key_triple).unwrap());
+        }
+        fs::remove_dir_all(store_path).unwrap();
+        read
+    }
+
+    #[test]
+    fn wide_key_ids_are_stored() {
+        for width in &[8, 16] {
+            let key_id = KeyId::random(*width).unwrap();
+            assert_eq!(key_id.as_bytes().len(), *width);
+            assert_eq!(
+                stored_and_read(&format!("key_id_{}", width), &[key_id]),
+                vec![key_id]
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_key_ids_are_read() {
+        // Before the width could be configured, key IDs were stored as a bincode `u32` and used
+        // as their big endian bytes on the token.
+        let stored = bincode::serialize(&0x1234_5678u32).unwrap();
+        let key_id = from_stored(&stored).unwrap();
+        assert_eq!(key_id.as_bytes(), &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(KeyId::from(0x1234_5678), key_id);
+
+        // 4 bytes key IDs are still stored in the same way.
+        let key_id = KeyId::from(7);
+        assert_eq!(stored_and_read("key_id_legacy", &[key_id]), vec![key_id]);
+    }
+
+    #[test]
+    fn key_id_of_invalid_width_is_rejected() {
+        assert_eq!(
+            from_stored(&[0; 5]).unwrap_err(),
+            ResponseStatus::InvalidEncoding
+        );
+        assert!(KeyId::from_bytes(&[0; 5]).is_none());
+        assert!(KeyId::random(5).is_none());
+    }
+
+    #[test]
+    fn key_ids_are_kept_after_width_change() {
+        let key_triple = |name: &str| {
+            KeyTriple::new(
+                ApplicationName::from_name("app".to_string()),
+                ProviderID::Pkcs11,
+                name.to_string(),
+            )
+        };
+
+        // Keys created while the width was 4, then 16 bytes, and a corrupt mapping.
+        let (client, store_path) = test_client("key_id_width_change");
+        let legacy = KeyId::from(7);
+        let wide = KeyId::random(16).unwrap();
+        insert_key_id(&client, key_triple("legacy"), legacy, test_key_attributes()).unwrap();
+        insert_key_id(&client, key_triple("wide"), wide, test_key_attributes()).unwrap();
+        client
+            .insert_key_info(key_triple("corrupt"), &[0u8; 5], test_key_attributes())
+            .unwrap();
+
+        // After a restart with a width of 8 bytes, all the valid keys are still there and the
+        // corrupt mapping is not deleted.
+        let (client, _) = test_client("key_id_width_change");
+        let mut key_triples = client.get_all().unwrap();
+        key_triples.sort_by_key(|key_triple| key_triple.key_name().to_string());
+        let mut stored = stored_key_ids(&client, &key_triples);
+        stored.sort_by_key(|(key_triple, _)| key_triple.key_name().to_string());
+        assert_eq!(
+            stored,
+            vec![(key_triple("legacy"), legacy), (key_triple("wide"), wide)]
+        );
+        assert!(client.get_key_info(&key_triple("corrupt")).is_ok());
+
+        fs::remove_dir_all(store_path).unwrap();
+    }
+}
@@ -1,33 +1,110 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
+use super::metrics::Counter;
 use super::utils::to_response_status;
-use super::{utils, KeyPairType, Provider};
+use super::{utils, KeyId, KeyPairType, Provider};
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::KeyTriple;
+use cryptoki::types::function::RvError;
 use cryptoki::types::mechanism::{Mechanism, MechanismType};
 use cryptoki::types::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
 use cryptoki::types::session::Session;
-use log::{error, info, trace};
-use parsec_interface::operations::psa_key_attributes::{Id, Lifetime, Type};
+use cryptoki::Error;
+use log::{debug, error, info, trace, warn};
+use parsec_interface::operations::list_keys::KeyInfo;
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, Id, Lifetime, Type, UsageFlags,
+};
 use parsec_interface::operations::{
     psa_destroy_key, psa_export_public_key, psa_generate_key, psa_import_key,
 };
 use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
 use parsec_interface::secrecy::ExposeSecret;
-use picky_asn1::wrapper::IntegerAsn1;
 use picky_asn1_x509::RSAPublicKey;
 use std::convert::{TryFrom, TryInto};
 
+/// Properties a key must have on the token for a guarded destroy to proceed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ExpectedKey {
+    /// Type of the key
+    pub key_type: Type,
+    /// Size of the key in bits
+    pub bits: usize,
+    /// ID of the key objects on the token
+    pub key_id: KeyId,
+}
+
+/// Modulus and public exponent of an RSA public key, as the unsigned big-endian bytes stored on
+/// the token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RsaPublicIntegers {
+    /// Modulus of the key
+    pub modulus: Vec<u8>,
+    /// Public exponent of the key
+    pub public_exponent: Vec<u8>,
+}
+
+/// Object of the token which destroying a key would remove.
+#[derive(Debug, Copy, Clone)]
+pub struct ObjectToDestroy {
+    /// Handle of the object in the session used for the dry run
+    pub handle: ObjectHandle,
+    /// Class of the object
+    pub class: ObjectClass,
+    /// ID of the object
+    pub key_id: KeyId,
+}
+
 impl Provider {
     /// Find the PKCS 11 object handle corresponding to the key ID and the key type (public,
     /// private or any key type) given as parameters for the current session.
+    ///
+    /// Handles found are cached. A cached handle is used if it still refers to an object with the
+    /// key ID, otherwise the key is looked up again on the token.
     pub(super) fn find_key(
         &self,
         session: &Session,
-        key_id: u32,
+        key_id: KeyId,
         key_type: KeyPairType,
     ) -> Result<ObjectHandle> {
-        let mut template = vec![Attribute::Id(key_id.to_be_bytes().into())];
+        let mut searched = false;
+        let object = self.handle_cache.lookup(
+            key_id,
+            key_type,
+            |object| self.has_key_id(session, object, key_id),
+            || {
+                searched = true;
+                self.find_key_objects(session, key_id, key_type)
+            },
+        );
+        if searched {
+            self.counters.increment(Counter::FindMiss);
+        } else if object.is_ok() {
+            self.counters.increment(Counter::FindHit);
+        }
+        object
+    }
+
+    /// Check that an object is usable and has the key ID.
+    fn has_key_id(&self, session: &Session, object: ObjectHandle, key_id: KeyId) -> bool {
+        trace!("GetAttributeValue command");
+        match session.get_attributes(object, &[AttributeType::Id]) {
+            Ok(attributes) => {
+                matches!(attributes.as_slice(), [Attribute::Id(id)] if id.as_slice() == key_id.as_bytes())
+            }
+            // The handle is invalid, for example if the object was destroyed outside of Parsec.
+            Err(_) => false,
+        }
+    }
+
+    /// Search the token for the object of the key.
+    fn find_key_objects(
+        &self,
+        session: &Session,
+        key_id: KeyId,
+        key_type: KeyPairType,
+    ) -> Result<ObjectHandle> {
+        let mut template = vec![Attribute::Id(key_id.as_bytes().into())];
 
         match key_type {
             KeyPairType::PublicKey => template.push(Attribute::Class(ObjectClass::PUBLIC_KEY)),
@@ -40,13 +117,79 @@ impl Provider {
             .find_objects(&template)
             .map_err(to_response_status)?;
 
-        if objects.is_empty() {
-            Err(ResponseStatus::PsaErrorDoesNotExist)
-        } else {
-            Ok(objects[0])
+        let object = utils::single_key_object(&key_type, &objects)?;
+
+        // Diagnostic only: the class is already part of the search template. It is only read for
+        // public keys, the classes of the other objects cannot be read with this version of the
+        // PKCS 11 crate.
+        if key_type == KeyPairType::PublicKey && crate::utils::GlobalConfig::log_error_details() {
+            let class = self.object_class(session, object)?;
+            trace!("Found object of class {:?}", class);
+            utils::check_object_class(&key_type, class)?;
+        }
+
+        Ok(object)
+    }
+
+    /// Read the class of an object. `None` is returned for classes other than public keys which
+    /// cannot be read with this version of the PKCS 11 crate.
+    fn object_class(&self, session: &Session, object: ObjectHandle) -> Result<Option<ObjectClass>> {
+        trace!("GetAttributeValue command");
+        match session.get_attributes(object, &[AttributeType::Class]) {
+            Ok(attributes) => match attributes.as_slice() {
+                [Attribute::Class(class)] => Ok(Some(*class)),
+                _ => {
+                    error!("Expected to find the class attribute of the object.");
+                    Err(ResponseStatus::PsaErrorCommunicationFailure)
+                }
+            },
+            Err(Error::NotSupported) => Ok(None),
+            Err(e) => Err(to_response_status(e)),
         }
     }
 
+    /// Check, if configured to, that the key type of an object on the token, and its size for RSA
+    /// keys, are the ones stored in the Key Info Manager for the key.
+    pub(super) fn check_key_attributes(
+        &self,
+        session: &Session,
+        object: ObjectHandle,
+        stored_attributes: Attributes,
+    ) -> Result<()> {
+        if !self.check_key_types {
+            return Ok(());
+        }
+        trace!("GetAttributeValue command");
+        let (token_type, modulus) = match session
+            .get_attributes(object, &[AttributeType::KeyType, AttributeType::Modulus])
+        {
+            Ok(attributes) => {
+                let mut token_type = None;
+                let mut modulus = None;
+                for attribute in attributes {
+                    match attribute {
+                        Attribute::KeyType(key_type) => token_type = Some(key_type),
+                        Attribute::Modulus(value) => modulus = Some(value),
+                        _ => (),
+                    }
+                }
+                if token_type.is_none() {
+                    error!("Expected to find the key type attribute of the object.");
+                    return Err(ResponseStatus::PsaErrorCommunicationFailure);
+                }
+                (token_type, modulus)
+            }
+            // Key types other than RSA cannot be read with this version of the PKCS 11 crate.
+            Err(Error::NotSupported) => (None, None),
+            Err(e) => return Err(to_response_status(e)),
+        };
+        utils::check_key_type(stored_attributes.key_type, token_type)?;
+        if token_type.is_some() {
+            utils::check_key_size(stored_attributes.bits, modulus.as_deref())?;
+        }
+        Ok(())
+    }
+
     pub(super) fn move_pub_key_to_psa_crypto(&self, key_triple: &KeyTriple) -> Result<Id> {
         info!("Attempting to export public key");
         let export_operation = psa_export_public_key::Operation {
@@ -82,28 +225,147 @@ impl Provider {
         Ok(())
     }
 
+    /// Generate a key pair whose objects carry the given `CKA_LABEL`.
+    pub fn psa_generate_key_with_label(
+        &self,
+        app_name: ApplicationName,
+        op: psa_generate_key::Operation,
+        label: &str,
+    ) -> Result<psa_generate_key::Result> {
+        trace!("psa_generate_key_with_label ingress");
+        let label = utils::sanitize_label(label)?;
+        self.psa_generate_key_internal(app_name, op, Some(&label), None)
+    }
+
+    /// Generate a key pair with different usage flags for its two halves: the ones of the
+    /// operation attributes apply to the private key and `public_usage_flags` to the public key.
+    ///
+    /// The union of both is recorded in the Key Info Manager. Fails with
+    /// `PsaErrorInvalidArgument` if the two policies are not consistent.
+    pub fn psa_generate_key_with_policies(
+        &self,
+        app_name: ApplicationName,
+        op: psa_generate_key::Operation,
+        public_usage_flags: UsageFlags,
+    ) -> Result<psa_generate_key::Result> {
+        trace!("psa_generate_key_with_policies ingress");
+        self.psa_generate_key_internal(app_name, op, None, Some(public_usage_flags))
+    }
+
+    /// Import a public key whose object carries the given `CKA_LABEL`.
+    pub fn psa_import_key_with_label(
+        &self,
+        app_name: ApplicationName,
+        op: psa_import_key::Operation,
+        label: &str,
+    ) -> Result<psa_import_key::Result> {
+        trace!("psa_import_key_with_label ingress");
+        let label = utils::sanitize_label(label)?;
+        self.psa_import_key_internal(app_name, op, Some(&label))
+    }
+
+    /// Find the IDs of the keys having objects with the given `CKA_LABEL`. Labels are not unique
+    /// so several keys can be found.
+    pub fn find_keys_by_label(&self, label: &str) -> Result<Vec<KeyId>> {
+        let label = utils::sanitize_label(label)?;
+        let session = self.new_session()?;
+
+        trace!("FindObjects commands");
+        let objects = session
+            .find_objects(&[Attribute::Label(label)])
+            .map_err(to_response_status)?;
+
+        let mut key_ids = Vec::new();
+        for object in objects {
+            trace!("GetAttributeValue command");
+            let attributes = session
+                .get_attributes(object, &[AttributeType::Id])
+                .map_err(to_response_status)?;
+            if let [Attribute::Id(id)] = attributes.as_slice() {
+                if let Some(key_id) = KeyId::from_bytes(id) {
+                    key_ids.push(key_id);
+                }
+            }
+        }
+        key_ids.sort_unstable();
+        key_ids.dedup();
+
+        Ok(key_ids)
+    }
+
+    /// Log if objects with the label given already exist on the token.
+    fn check_label_in_use(&self, session: &Session, label: &[u8]) -> Result<()> {
+        trace!("FindObjects commands");
+        let objects = session
+            .find_objects(&[Attribute::Label(label.to_vec())])
+            .map_err(to_response_status)?;
+        if !objects.is_empty() {
+            warn!(
+                "{} objects already have the label of the new key on the token.",
+                objects.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the objects generated carry the key ID, if configured to.
+    fn check_generated_key_id(
+        &self,
+        session: &Session,
+        key_id: KeyId,
+        objects: &[ObjectHandle],
+    ) -> Result<()> {
+        if !self.check_generated_key_ids {
+            return Ok(());
+        }
+        for object in objects {
+            trace!("GetAttributeValue command");
+            let attributes = session
+                .get_attributes(*object, &[AttributeType::Id])
+                .map_err(to_response_status)?;
+            utils::check_key_id(key_id, attributes)?;
+        }
+        Ok(())
+    }
+
     pub(super) fn psa_generate_key_internal(
         &self,
         app_name: ApplicationName,
         op: psa_generate_key::Operation,
+        label: Option<&[u8]>,
+        public_usage_flags: Option<UsageFlags>,
     ) -> Result<psa_generate_key::Result> {
+        self.check_private_operations_allowed()?;
+        self.check_token_writes_allowed()?;
         if op.attributes.key_type != Type::RsaKeyPair {
-            error!("The PKCS11 provider currently only supports creating RSA key pairs.");
+            debug!("The PKCS11 provider currently only supports creating RSA key pairs.");
             return Err(ResponseStatus::PsaErrorNotSupported);
         }
 
         let key_name = op.key_name;
-        let key_attributes = op.attributes;
+        let mut key_attributes = op.attributes;
+        let private_usage_flags = key_attributes.policy.usage_flags;
+        let public_usage_flags = match public_usage_flags {
+            Some(public_usage_flags) => {
+                utils::check_key_pair_policies(public_usage_flags, private_usage_flags)?;
+                key_attributes.policy.usage_flags =
+                    utils::usage_flags_union(public_usage_flags, private_usage_flags);
+                public_usage_flags
+            }
+            None => private_usage_flags,
+        };
 
+        self.key_name_rules.check(&key_name)?;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
         self.key_info_store.does_not_exist(&key_triple)?;
 
         let session = self.new_session()?;
 
-        let key_id = self.create_key_id();
+        let reserved_id = self.create_key_id(&session, &key_triple, &key_attributes)?;
+        let key_id = reserved_id.id();
 
         let mut pub_template = vec![
-            Attribute::Id(key_id.to_be_bytes().into()),
+            Attribute::Id(key_id.as_bytes().into()),
             Attribute::Token(true.into()),
             Attribute::AllowedMechanisms(vec![Mechanism::try_from(
                 key_attributes.policy.permitted_algorithms,
@@ -111,10 +373,15 @@ impl Provider {
             .map_err(to_response_status)?
             .mechanism_type()]),
         ];
+        if let Some(label) = label {
+            self.check_label_in_use(&session, label)?;
+            pub_template.push(Attribute::Label(label.to_vec()));
+        }
         let mut priv_template = pub_template.clone();
 
-        utils::key_pair_usage_flags_to_pkcs11_attributes(
-            key_attributes.policy.usage_flags,
+        utils::key_pair_policies_to_pkcs11_attributes(
+            public_usage_flags,
+            private_usage_flags,
             &mut pub_template,
             &mut priv_template,
         );
@@ -122,7 +389,7 @@ impl Provider {
         let mech = match key_attributes.key_type {
             Type::RsaKeyPair => {
                 pub_template.push(Attribute::Private(false.into()));
-                pub_template.push(Attribute::PublicExponent(utils::PUBLIC_EXPONENT.into()));
+                pub_template.push(Attribute::PublicExponent(self.public_exponent.clone()));
                 pub_template.push(Attribute::ModulusBits(
                     key_attributes.bits.try_into().map_err(to_response_status)?,
                 ));
@@ -131,13 +398,17 @@ impl Provider {
             _ => Err(ResponseStatus::PsaErrorNotSupported),
         }?;
 
-        match session.generate_key_pair(&mech, &pub_template, &priv_template) {
+        match self.with_relogin(&session, || {
+            session.generate_key_pair(&mech, &pub_template, &priv_template)
+        }) {
             Ok((public, private)) => {
-                if let Err(e) =
-                    self.key_info_store
-                        .insert_key_info(key_triple, &key_id, key_attributes)
+                if let Err(e) = self
+                    .check_generated_key_id(&session, key_id, &[public, private])
+                    .and_then(|_| {
+                        reserved_id.commit(&self.key_info_store, key_triple, key_attributes)
+                    })
                 {
-                    format_error!("Failed to insert the mappings, deleting the key.", e);
+                    format_error!("Failed to store the new key, deleting it.", e);
                     if let Err(e) = session.destroy_object(public) {
                         format_error!("Failed to destroy public part of the key: ", e);
                     }
@@ -146,9 +417,17 @@ impl Provider {
                     }
                     Err(e)
                 } else {
+                    self.counters.increment(Counter::GeneratedKey);
                     Ok(psa_generate_key::Result {})
                 }
             }
+            Err(Error::Pkcs11(RvError::AttributeValueInvalid))
+            | Err(Error::Pkcs11(RvError::TemplateInconsistent))
+                if self.public_exponent != utils::PUBLIC_EXPONENT =>
+            {
+                error!("The token rejected the configured RSA public exponent.");
+                Err(ResponseStatus::PsaErrorNotSupported)
+            }
             Err(error) => {
                 format_error!("Generate key status: ", error);
                 Err(to_response_status(error))
@@ -160,11 +439,14 @@ impl Provider {
         &self,
         app_name: ApplicationName,
         op: psa_import_key::Operation,
+        label: Option<&[u8]>,
     ) -> Result<psa_import_key::Result> {
+        self.check_private_operations_allowed()?;
+        self.check_token_writes_allowed()?;
         match op.attributes.key_type {
-            Type::RsaPublicKey => self.psa_import_key_internal_rsa_public(app_name, op),
+            Type::RsaPublicKey => self.psa_import_key_internal_rsa_public(app_name, op, label),
             _ => {
-                error!(
+                debug!(
                     "The pkcs11 provider does not support the {:?} key type.",
                     op.attributes.key_type
                 );
@@ -177,16 +459,19 @@ impl Provider {
         &self,
         app_name: ApplicationName,
         op: psa_import_key::Operation,
+        label: Option<&[u8]>,
     ) -> Result<psa_import_key::Result> {
         let key_name = op.key_name;
         let key_attributes = op.attributes;
+        self.key_name_rules.check(&key_name)?;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
 
         self.key_info_store.does_not_exist(&key_triple)?;
 
         let session = self.new_session()?;
 
-        let key_id = self.create_key_id();
+        let reserved_id = self.create_key_id(&session, &key_triple, &key_attributes)?;
+        let key_id = reserved_id.id();
 
         let mut template: Vec<Attribute> = Vec::new();
 
@@ -225,16 +510,29 @@ impl Provider {
         template.push(Attribute::PublicExponent(exponent_object.into()));
         template.push(Attribute::Verify(true.into()));
         template.push(Attribute::Encrypt(true.into()));
-        template.push(Attribute::Id(key_id.to_be_bytes().into()));
+        template.push(Attribute::Id(key_id.as_bytes().into()));
         template.push(Attribute::Private(false.into()));
         template.push(Attribute::AllowedMechanisms(vec![MechanismType::RSA_PKCS]));
+        if let Some(label) = label {
+            self.check_label_in_use(&session, label)?;
+            template.push(Attribute::Label(label.to_vec()));
+        }
 
         trace!("CreateObject command");
         match session.create_object(&template) {
             Ok(key) => {
-                if let Err(e) =
-                    self.key_info_store
-                        .insert_key_info(key_triple, &key_id, key_attributes)
+                if self.check_imported_keys {
+                    let nonce = rand::random::<[u8; 16]>();
+                    trace!("Encrypt command");
+                    if let Err(e) = session.encrypt(&Mechanism::RsaPkcs, key, &nonce) {
+                        format_error!("Imported key not usable for encryption, deleting it.", e);
+                        if let Err(e) = session.destroy_object(key) {
+                            format_error!("Failed to destroy public key: ", e);
+                        }
+                        return Err(ResponseStatus::PsaErrorInvalidArgument);
+                    }
+                }
+                if let Err(e) = reserved_id.commit(&self.key_info_store, key_triple, key_attributes)
                 {
                     format_error!("Failed to insert the mappings, deleting the key.", e);
                     if let Err(e) = session.destroy_object(key) {
@@ -242,6 +540,7 @@ impl Provider {
                     }
                     Err(e)
                 } else {
+                    self.counters.increment(Counter::ImportedKey);
                     Ok(psa_import_key::Result {})
                 }
             }
@@ -259,81 +558,343 @@ impl Provider {
     ) -> Result<psa_export_public_key::Result> {
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let key_id = self.key_info_store.get_key_id(&key_triple)?;
+        let key_id = self.get_key_id(&key_triple)?;
+        let _key_use = self.key_users.acquire(key_id)?;
+
+        let session = self.new_session()?;
+        let data = self.export_public_key_data(&session, &key_triple, key_id)?;
+        self.counters.increment(Counter::ExportedKey);
+        Ok(psa_export_public_key::Result { data: data.into() })
+    }
+
+    /// Export the modulus and the public exponent of an RSA key as the unsigned big-endian bytes
+    /// stored on the token, for the clients doing their own encoding. `psa_export_public_key`
+    /// returns them DER-encoded.
+    pub fn export_public_key_integers(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+    ) -> Result<RsaPublicIntegers> {
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
+        let key_id = self.get_key_id(&key_triple)?;
+        let _key_use = self.key_users.acquire(key_id)?;
 
         let session = self.new_session()?;
+        let integers = self.read_public_integers(&session, &key_triple, key_id)?;
+        self.counters.increment(Counter::ExportedKey);
+        Ok(integers)
+    }
 
-        let key = self.find_key(&session, key_id, KeyPairType::PublicKey)?;
+    /// Read the public part of a key from the token, in the format of `psa_export_public_key`.
+    /// The caller holds the key.
+    pub(super) fn export_public_key_data(
+        &self,
+        session: &Session,
+        key_triple: &KeyTriple,
+        key_id: KeyId,
+    ) -> Result<Vec<u8>> {
+        let integers = self.read_public_integers(session, key_triple, key_id)?;
+        utils::rsa_public_key_der(integers.modulus, integers.public_exponent)
+    }
+
+    /// Read the modulus and the public exponent of a key from the token. The caller holds the key.
+    fn read_public_integers(
+        &self,
+        session: &Session,
+        key_triple: &KeyTriple,
+        key_id: KeyId,
+    ) -> Result<RsaPublicIntegers> {
+        // Only the public object is used: the policy of the private one, if any, does not matter.
+        let key = self.find_key(session, key_id, KeyPairType::PublicKey)?;
         info!("Located key for export.");
+        if self.check_key_types {
+            let key_attributes = self.key_info_store.get_key_attributes(key_triple)?;
+            self.check_key_attributes(session, key, key_attributes)?;
+        }
 
-        let mut attributes = session
-            .get_attributes(
-                key,
-                &[AttributeType::Modulus, AttributeType::PublicExponent],
-            )
+        utils::check_public_attributes(&utils::RSA_PUBLIC_KEY_ATTRIBUTES)?;
+        let attributes = session
+            .get_attributes(key, &utils::RSA_PUBLIC_KEY_ATTRIBUTES)
             .map_err(to_response_status)?;
 
-        if attributes.len() != 2 {
-            error!("Expected to find modulus and public exponent attributes in public key.");
-            return Err(ResponseStatus::PsaErrorCommunicationFailure);
+        let (modulus, public_exponent) = utils::rsa_public_key_parts(attributes)?;
+
+        Ok(RsaPublicIntegers {
+            modulus,
+            public_exponent,
+        })
+    }
+
+    /// List the keys of an application with their attributes.
+    ///
+    /// The size of RSA keys is read back from the token. If it differs from the stored one, for
+    /// example because the key was changed outside of Parsec, the token is trusted. The stored
+    /// size is kept for the keys which cannot be read from the token.
+    pub(super) fn list_keys_internal(&self, app_name: ApplicationName) -> Result<Vec<KeyInfo>> {
+        let mut keys = self.key_info_store.list_keys(&app_name)?;
+        if !keys.iter().any(|key| is_rsa(key.attributes.key_type)) {
+            return Ok(keys);
         }
 
-        let modulus = if let Attribute::Modulus(vec) = attributes.remove(0) {
-            IntegerAsn1::from_bytes_be_unsigned(vec)
-        } else {
-            error!("Expected to find modulus attribute.");
-            return Err(ResponseStatus::PsaErrorCommunicationFailure);
+        let session = self.new_read_only_session()?;
+        for key in keys
+            .iter_mut()
+            .filter(|key| is_rsa(key.attributes.key_type))
+        {
+            let key_triple = KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key.name.clone());
+            let token_key = self
+                .get_key_id(&key_triple)
+                .and_then(|key_id| self.token_key(&session, key_id));
+            match token_key {
+                Ok(token_key) => {
+                    key.attributes = utils::reconcile_key_size(key.attributes, token_key.bits)
+                }
+                Err(e) => format_error!("Failed to read the size of a key from the token", e),
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Read the type and size of the key with the given ID from the token.
+    fn token_key(&self, session: &Session, key_id: KeyId) -> Result<ExpectedKey> {
+        let public_key = self.find_key(session, key_id, KeyPairType::PublicKey)?;
+        let key_type = match self.find_key(session, key_id, KeyPairType::PrivateKey) {
+            Ok(_) => Type::RsaKeyPair,
+            Err(ResponseStatus::PsaErrorDoesNotExist) => Type::RsaPublicKey,
+            Err(e) => return Err(e),
         };
-        let public_exponent = if let Attribute::PublicExponent(vec) = attributes.remove(0) {
-            IntegerAsn1::from_bytes_be_unsigned(vec)
-        } else {
-            error!("Expected to find public exponent attribute.");
-            return Err(ResponseStatus::PsaErrorCommunicationFailure);
+
+        trace!("GetAttributeValue command");
+        let attributes = session
+            .get_attributes(public_key, &[AttributeType::Modulus])
+            .map_err(to_response_status)?;
+        let bits = match attributes.as_slice() {
+            [Attribute::Modulus(modulus)] => utils::integer_bits(modulus),
+            _ => {
+                error!("Expected to find the modulus attribute in public key.");
+                return Err(ResponseStatus::PsaErrorCommunicationFailure);
+            }
         };
 
-        let key = RSAPublicKey {
-            modulus,
-            public_exponent,
+        Ok(ExpectedKey {
+            key_type,
+            bits,
+            key_id,
+        })
+    }
+
+    /// List the objects of the token that destroying a key would remove, without destroying
+    /// anything.
+    pub fn psa_destroy_key_dry_run(
+        &self,
+        app_name: ApplicationName,
+        op: psa_destroy_key::Operation,
+    ) -> Result<Vec<ObjectToDestroy>> {
+        trace!("psa_destroy_key_dry_run ingress");
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, op.key_name);
+        let key_id: KeyId = self.get_key_id(&key_triple)?;
+        let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
+
+        let session = self.new_session()?;
+        let find_class = |class: ObjectClass| {
+            trace!("FindObjects commands");
+            session
+                .find_objects(&[
+                    Attribute::Id(key_id.as_bytes().into()),
+                    Attribute::Class(class),
+                ])
+                .map_err(to_response_status)
         };
-        let data = picky_asn1_der::to_vec(&key).map_err(|err| {
-            format_error!("Could not serialise key elements", err);
-            ResponseStatus::PsaErrorCommunicationFailure
-        })?;
-        Ok(psa_export_public_key::Result { data: data.into() })
+        let public = find_class(ObjectClass::PUBLIC_KEY)?;
+        let private = find_class(ObjectClass::PRIVATE_KEY)?;
+        let objects: Vec<ObjectToDestroy> = classify_objects(&public, &private)
+            .into_iter()
+            .map(|(handle, class)| ObjectToDestroy {
+                handle,
+                class,
+                key_id,
+            })
+            .collect();
+
+        let expected_objects = utils::key_objects_count(key_attributes.key_type);
+        if objects.len() != expected_objects {
+            warn!(
+                "Expected {} objects for the key but found {}.",
+                expected_objects,
+                objects.len()
+            );
+        }
+        info!(
+            "Dry run: destroying the key would remove {} objects.",
+            objects.len()
+        );
+
+        Ok(objects)
+    }
+
+    /// Destroy a key only if its objects on the token still match the `expected` properties.
+    ///
+    /// Nothing is destroyed and `PsaErrorInvalidArgument` is returned on mismatch.
+    pub fn psa_destroy_key_guarded(
+        &self,
+        app_name: ApplicationName,
+        op: psa_destroy_key::Operation,
+        expected: &ExpectedKey,
+    ) -> Result<psa_destroy_key::Result> {
+        trace!("psa_destroy_key_guarded ingress");
+        self.psa_destroy_key_internal(app_name, op, Some(expected))
     }
 
     pub(super) fn psa_destroy_key_internal(
         &self,
         app_name: ApplicationName,
         op: psa_destroy_key::Operation,
+        expected: Option<&ExpectedKey>,
     ) -> Result<psa_destroy_key::Result> {
+        self.check_private_operations_allowed()?;
+        self.check_token_writes_allowed()?;
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let key_id = self.key_info_store.get_key_id(&key_triple)?;
-
-        let _ = self.key_info_store.remove_key_info(&key_triple)?;
+        let key_id = self.get_key_id(&key_triple)?;
+        // Operations using the key keep its objects until they are finished.
+        let _destroying = self.key_users.destroying(key_id);
+        let key_attributes = self.key_info_store.get_key_attributes(&key_triple)?;
 
         let session = self.new_session()?;
 
-        let first_key = self.find_key(&session, key_id, KeyPairType::Any)?;
-        session
-            .destroy_object(first_key)
-            .map_err(to_response_status)?;
+        if let Some(expected) = expected {
+            check_expected_key(&self.token_key(&session, key_id)?, expected)?;
+        }
 
-        // Second key is optional.
-        match self.find_key(&session, key_id, KeyPairType::Any) {
-            Ok(key) => {
-                trace!("DestroyObject command");
-                session.destroy_object(key).map_err(to_response_status)
+        let _ = self.key_info_store.remove_key_info(&key_triple)?;
+        self.verify_cache.invalidate_key(key_id);
+        self.signed_hashes.forget_key(key_id);
+
+        let expected_objects = utils::key_objects_count(key_attributes.key_type);
+        let mut destroyed_objects = 0;
+        // Public keys imported alone have no private part.
+        for (key_type, part) in vec![
+            (KeyPairType::PrivateKey, "Private"),
+            (KeyPairType::PublicKey, "Public"),
+        ] {
+            match self.find_key(&session, key_id, key_type) {
+                Ok(key) => {
+                    trace!("DestroyObject command");
+                    session.destroy_object(key).map_err(to_response_status)?;
+                    info!("{} part of the key destroyed successfully.", part);
+                    destroyed_objects += 1;
+                }
+                Err(ResponseStatus::PsaErrorDoesNotExist) => (),
+                Err(e) => {
+                    format_error!("Error destroying key", e);
+                    return Err(e);
+                }
             }
-            // A second key is optional.
-            Err(ResponseStatus::PsaErrorDoesNotExist) => Ok(()),
-            Err(e) => {
-                format_error!("Error destroying key", e);
-                Err(e)
+        }
+
+        self.handle_cache.invalidate_key(key_id);
+        self.fingerprint_cache.invalidate_key(key_id);
+
+        if destroyed_objects == 0 {
+            error!("No object found in the PKCS 11 library for the key to destroy.");
+            return Err(ResponseStatus::PsaErrorDoesNotExist);
+        }
+        if destroyed_objects < expected_objects {
+            if crate::utils::GlobalConfig::log_error_details() {
+                warn!(
+                    "Expected {} objects for key {} but only found {}.",
+                    expected_objects, key_triple, destroyed_objects
+                );
+            } else {
+                warn!("Found fewer objects than expected for the key to destroy.");
             }
-        }?;
+        }
 
+        self.counters.increment(Counter::DestroyedKey);
         Ok(psa_destroy_key::Result {})
     }
 }
+
+/// Whether the size of keys of this type can be read from the token.
+fn is_rsa(key_type: Type) -> bool {
+    matches!(key_type, Type::RsaKeyPair | Type::RsaPublicKey)
+}
+
+/// Tag the public and private objects found for a key with their class.
+fn classify_objects<H: Copy>(public: &[H], private: &[H]) -> Vec<(H, ObjectClass)> {
+    public
+        .iter()
+        .map(|handle| (*handle, ObjectClass::PUBLIC_KEY))
+        .chain(
+            private
+                .iter()
+                .map(|handle| (*handle, ObjectClass::PRIVATE_KEY)),
+        )
+        .collect()
+}
+
+/// Check that the key found on the token is the one expected.
+fn check_expected_key(actual: &ExpectedKey, expected: &ExpectedKey) -> Result<()> {
+    if actual != expected {
+        if crate::utils::GlobalConfig::log_error_details() {
+            error!(
+                "Key on the token ({:?}) does not match the expected one ({:?}), not destroying it.",
+                actual, expected
+            );
+        } else {
+            error!("Key on the token does not match the expected one, not destroying it.");
+        }
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_expected_key, classify_objects, ExpectedKey, KeyId};
+    use cryptoki::types::object::ObjectClass;
+    use parsec_interface::operations::psa_key_attributes::Type;
+    use parsec_interface::requests::ResponseStatus;
+
+    #[test]
+    fn guarded_destroy_checks_all_properties() {
+        let actual = ExpectedKey {
+            key_type: Type::RsaKeyPair,
+            bits: 2048,
+            key_id: KeyId::from(0x1234),
+        };
+        assert!(check_expected_key(&actual, &actual).is_ok());
+
+        for expected in &[
+            ExpectedKey {
+                key_type: Type::RsaPublicKey,
+                ..actual
+            },
+            ExpectedKey {
+                bits: 1024,
+                ..actual
+            },
+            ExpectedKey {
+                key_id: KeyId::from(0x4321),
+                ..actual
+            },
+        ] {
+            assert_eq!(
+                check_expected_key(&actual, expected).unwrap_err(),
+                ResponseStatus::PsaErrorInvalidArgument
+            );
+        }
+    }
+
+    #[test]
+    fn dry_run_reports_both_halves_of_key_pair() {
+        let objects = classify_objects(&[3u64], &[4u64]);
+
+        assert_eq!(
+            objects,
+            vec![(3, ObjectClass::PUBLIC_KEY), (4, ObjectClass::PRIVATE_KEY)]
+        );
+        assert_eq!(classify_objects::<u64>(&[7], &[]).len(), 1);
+    }
+}
@@ -1,15 +1,1019 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
-use super::Provider;
+use super::utils::to_response_status;
+use super::{key_id, IdDiscrepancy, KeyId, LocalIdStore, Provider};
+use crate::authenticators::{Application, ApplicationName};
+use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
+use cryptoki::types::object::{Attribute, AttributeType, ObjectClass};
+use cryptoki::types::session::Session;
+use log::{error, info, trace, warn};
+use parsec_interface::operations::psa_algorithm::Hash;
+use parsec_interface::operations::psa_key_attributes::Attributes;
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use std::collections::HashSet;
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+/// Number of key IDs tried before giving up when they are already used on the token.
+const KEY_ID_ATTEMPTS: usize = 8;
+
+/// Number of salts tried by the deterministic allocator before falling back to a random key ID.
+const DETERMINISTIC_KEY_ID_SALTS: u32 = 16;
+
+/// Strategy used to pick the IDs of new keys.
+pub trait KeyIdAllocator: Send + Sync {
+    /// Propose an ID of `width` bytes, one of `KEY_ID_WIDTHS`, for a new key. The proposed ID
+    /// must not be one of the `taken` ones.
+    fn propose(
+        &self,
+        key_triple: &KeyTriple,
+        attributes: &Attributes,
+        width: usize,
+        taken: &HashSet<KeyId>,
+    ) -> KeyId;
+}
+
+/// Allocator picking random key IDs. This is the default allocator.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RandomKeyIdAllocator;
+
+impl RandomKeyIdAllocator {
+    fn key_id(width: usize) -> KeyId {
+        KeyId::random(width).unwrap_or_else(|| KeyId::from(rand::random::<u32>()))
+    }
+}
+
+impl KeyIdAllocator for RandomKeyIdAllocator {
+    fn propose(
+        &self,
+        _key_triple: &KeyTriple,
+        _attributes: &Attributes,
+        width: usize,
+        taken: &HashSet<KeyId>,
+    ) -> KeyId {
+        let mut key_id = RandomKeyIdAllocator::key_id(width);
+        while taken.contains(&key_id) {
+            key_id = RandomKeyIdAllocator::key_id(width);
+        }
+        key_id
+    }
+}
+
+/// Allocator deriving the IDs of new keys from a hash of their key triple, so that the same key
+/// gets the same ID on every token.
+///
+/// The ID is the first bytes, as many as the key ID width, of the SHA-256 of the application and
+/// key names. Taken IDs are skipped by hashing them again with an increasing salt, up to
+/// `DETERMINISTIC_KEY_ID_SALTS` times before a random ID is picked.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DeterministicKeyIdAllocator;
+
+impl DeterministicKeyIdAllocator {
+    fn key_id(
+        key_triple: &KeyTriple,
+        width: usize,
+        salt: u32,
+    ) -> psa_crypto::types::status::Result<KeyId> {
+        // Length prefixes keep ("ab", "c") and ("a", "bc") apart.
+        let mut input = Vec::new();
+        for field in &[
+            key_triple.app_name().as_bytes(),
+            key_triple.key_name().as_bytes(),
+        ] {
+            input.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            input.extend_from_slice(field);
+        }
+        input.extend_from_slice(&salt.to_be_bytes());
+
+        let mut hash = [0; 32];
+        let _ = psa_crypto::operations::hash::hash_compute(Hash::Sha256, &input, &mut hash)?;
+        hash.get(..width)
+            .and_then(KeyId::from_bytes)
+            .ok_or(psa_crypto::types::status::Error::InvalidArgument)
+    }
+}
+
+impl KeyIdAllocator for DeterministicKeyIdAllocator {
+    fn propose(
+        &self,
+        key_triple: &KeyTriple,
+        attributes: &Attributes,
+        width: usize,
+        taken: &HashSet<KeyId>,
+    ) -> KeyId {
+        match psa_crypto::init() {
+            Ok(()) => {
+                for salt in 0..DETERMINISTIC_KEY_ID_SALTS {
+                    match DeterministicKeyIdAllocator::key_id(key_triple, width, salt) {
+                        Ok(key_id) if !taken.contains(&key_id) => return key_id,
+                        Ok(_) => trace!("Key ID taken, salting the key triple."),
+                        Err(e) => {
+                            format_error!("Failed to hash the key triple", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => format_error!("Failed to initialise the hash backend", e),
+        }
+        warn!("Falling back to a random key ID.");
+        RandomKeyIdAllocator.propose(key_triple, attributes, width, taken)
+    }
+}
 
 impl Provider {
-    pub(super) fn create_key_id(&self) -> u32 {
+    /// Reserve a key ID for a new key. The ID is released if the key is not stored.
+    ///
+    /// IDs already carried by objects on the token, for example created outside of Parsec, are
+    /// skipped.
+    pub(super) fn create_key_id(
+        &self,
+        session: &Session,
+        key_triple: &KeyTriple,
+        attributes: &Attributes,
+    ) -> Result<ReservedKeyId<'_>> {
+        reserve_key_id(
+            self.key_id_allocator.as_ref(),
+            key_triple,
+            attributes,
+            self.key_id_width,
+            &self.local_ids,
+            &self.reserved_ids,
+            |key_id| {
+                if self
+                    .unmapped_ids
+                    .read()
+                    .expect("Unmapped ID lock poisoned")
+                    .contains(&key_id)
+                {
+                    return Ok(true);
+                }
+                self.key_id_on_token(session, key_id)
+            },
+        )
+    }
+
+    /// Get the ID of a key from the Key Info Manager.
+    pub(super) fn get_key_id(&self, key_triple: &KeyTriple) -> Result<KeyId> {
+        key_id::stored_key_id(&self.key_info_store, key_triple)
+    }
+
+    /// Check if objects with the key ID exist on the token.
+    fn key_id_on_token(&self, session: &Session, key_id: KeyId) -> Result<bool> {
+        trace!("FindObjects commands");
+        let objects = session
+            .find_objects(&[Attribute::Id(key_id.as_bytes().into())])
+            .map_err(to_response_status)?;
+        Ok(!objects.is_empty())
+    }
+
+    /// Find the IDs of the key objects on the token which are not referenced in the Key Info
+    /// Manager, for example keys of another Parsec host sharing the token or left by a key
+    /// creation interrupted before its mapping was stored. They are kept apart from the local key
+    /// IDs, only to be skipped for new keys.
+    pub(super) fn find_unmapped_ids(&self) -> Result<()> {
+        let session = self.new_read_only_session()?;
+        let mut token_ids = HashSet::new();
+        for class in &[ObjectClass::PRIVATE_KEY, ObjectClass::PUBLIC_KEY] {
+            trace!("FindObjects commands");
+            let objects = session
+                .find_objects(&[Attribute::Class(*class)])
+                .map_err(to_response_status)?;
+            for object in objects {
+                trace!("GetAttributeValue command");
+                let attributes = session
+                    .get_attributes(object, &[AttributeType::Id])
+                    .map_err(to_response_status)?;
+                // Objects without an ID of one of the key ID widths were not created by Parsec.
+                if let [Attribute::Id(id)] = attributes.as_slice() {
+                    if let Some(key_id) = KeyId::from_bytes(id) {
+                        let _ = token_ids.insert(key_id);
+                    }
+                }
+            }
+        }
+        let local_ids_handle = self.local_ids.read().expect("Local ID lock poisoned");
+        let mut unmapped_ids_handle = self
+            .unmapped_ids
+            .write()
+            .expect("Unmapped ID lock poisoned");
+        unmapped_ids_handle.extend(token_ids.difference(&local_ids_handle));
+        if !unmapped_ids_handle.is_empty() {
+            info!(
+                "{} key IDs on the token are not referenced in the Key Info Manager.",
+                unmapped_ids_handle.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the local key IDs are exactly the ones referenced in the Key Info Manager and
+    /// handle the discrepancies as configured. The IDs reserved for keys being created are not
+    /// discrepancies. Returns the number of discrepancies found.
+    pub fn check_local_ids(&self) -> Result<usize> {
         let mut local_ids_handle = self.local_ids.write().expect("Local ID lock poisoned");
-        let mut key_id = rand::random::<u32>();
-        while local_ids_handle.contains(&key_id) {
-            key_id = rand::random::<u32>();
+        // Reservations are committed by storing their mapping before they are released: taken
+        // in this order, a reservation committed meanwhile is either still reserved or stored.
+        let reserved_ids = self
+            .reserved_ids
+            .lock()
+            .expect("Reserved ID lock poisoned")
+            .clone();
+        let mut stored_ids = HashSet::new();
+        for key_triple in self.key_info_store.get_all()? {
+            // Entries with an invalid key ID are reported when they are used.
+            if let Ok(key_id) = self.get_key_id(&key_triple) {
+                let _ = stored_ids.insert(key_id);
+            }
         }
-        let _ = local_ids_handle.insert(key_id);
-        key_id
+        reconcile_key_ids(
+            &mut local_ids_handle,
+            &stored_ids,
+            &reserved_ids,
+            self.id_discrepancy,
+        )
+    }
+
+    /// Get the time at which a key was generated or imported, as recorded in the Key Info Manager.
+    /// Returns `None` for the keys stored before creation times were recorded.
+    pub fn key_creation_time(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+    ) -> Result<Option<SystemTime>> {
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
+        self.key_info_store.get_key_creation_time(&key_triple)
+    }
+
+    /// List the applications having keys in this provider with the number of keys each of them
+    /// owns. Only admins are allowed to get this roster.
+    pub fn application_roster(&self, app: &Application) -> Result<Vec<(ApplicationName, usize)>> {
+        if !app.is_admin() {
+            error!("Only admins can list the applications having keys in the provider.");
+            return Err(ResponseStatus::AdminOperation);
+        }
+        self.key_info_store.list_clients_key_counts()
+    }
+
+    /// Dump the key IDs currently in use, in ascending order.
+    ///
+    /// Only available in tests, to help diagnosing key ID collisions.
+    #[cfg(test)]
+    pub fn local_ids_snapshot(&self) -> Vec<KeyId> {
+        local_ids_snapshot(&self.local_ids)
+    }
+
+    /// Replace the key IDs currently in use with the ones given.
+    ///
+    /// Only available in tests, to reproduce key ID allocation issues from a known state.
+    #[cfg(test)]
+    pub fn restore_local_ids(&self, key_ids: &[KeyId]) {
+        restore_local_ids(&self.local_ids, key_ids)
+    }
+}
+
+/// Key ID reserved for a new key.
+///
+/// The key mapping is only stored when the reservation is committed, once the key is created on
+/// the token. Unless committed, the ID is released when the reservation is dropped, so that every
+/// early return of the key creation leaves neither the ID nor the mapping behind.
+#[derive(Debug)]
+pub(super) struct ReservedKeyId<'a> {
+    local_ids: &'a RwLock<LocalIdStore>,
+    reserved_ids: &'a Mutex<HashSet<KeyId>>,
+    key_id: KeyId,
+    committed: bool,
+}
+
+impl ReservedKeyId<'_> {
+    /// The ID reserved.
+    pub(super) fn id(&self) -> KeyId {
+        self.key_id
+    }
+
+    /// Store the mapping of the key to the ID in the Key Info Manager and keep the ID. If the
+    /// mapping cannot be stored, nothing is kept and the ID is released.
+    pub(super) fn commit(
+        mut self,
+        key_info_store: &KeyInfoManagerClient,
+        key_triple: KeyTriple,
+        attributes: Attributes,
+    ) -> Result<()> {
+        key_id::insert_key_id(key_info_store, key_triple, self.key_id, attributes)?;
+        let _ = self
+            .reserved_ids
+            .lock()
+            .expect("Reserved ID lock poisoned")
+            .remove(&self.key_id);
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for ReservedKeyId<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            release_key_ids(self.local_ids, self.reserved_ids, &[self.key_id]);
+        }
+    }
+}
+
+/// Reserve a key ID from the allocator which is neither in use locally nor `on_token`.
+///
+/// The allocator is asked for another ID when the token already has one, up to
+/// `KEY_ID_ATTEMPTS` times. The local IDs are only locked while picking a candidate and while
+/// releasing the ones found on the token, not while the token is searched.
+fn reserve_key_id<'a>(
+    allocator: &dyn KeyIdAllocator,
+    key_triple: &KeyTriple,
+    attributes: &Attributes,
+    width: usize,
+    local_ids: &'a RwLock<LocalIdStore>,
+    reserved_ids: &'a Mutex<HashSet<KeyId>>,
+    mut on_token: impl FnMut(KeyId) -> Result<bool>,
+) -> Result<ReservedKeyId<'a>> {
+    // IDs found on the token stay in the local IDs while looking for a free one, for the
+    // allocator not to propose them again.
+    let mut colliding = Vec::new();
+    let mut reserved = Err(ResponseStatus::PsaErrorInsufficientStorage);
+    for _ in 0..KEY_ID_ATTEMPTS {
+        let mut local_ids_handle = local_ids.write().expect("Local ID lock poisoned");
+        let key_id = match allocate_key_id(
+            allocator,
+            key_triple,
+            attributes,
+            width,
+            &mut local_ids_handle,
+        ) {
+            Ok(key_id) => {
+                let _ = reserved_ids
+                    .lock()
+                    .expect("Reserved ID lock poisoned")
+                    .insert(key_id);
+                drop(local_ids_handle);
+                key_id
+            }
+            Err(e) => {
+                reserved = Err(e);
+                break;
+            }
+        };
+        match on_token(key_id) {
+            Ok(false) => {
+                reserved = Ok(key_id);
+                break;
+            }
+            Ok(true) => {
+                warn!("Objects with the new key ID already exist on the token, trying another ID.");
+                colliding.push(key_id);
+            }
+            Err(e) => {
+                colliding.push(key_id);
+                reserved = Err(e);
+                break;
+            }
+        }
+    }
+    release_key_ids(local_ids, reserved_ids, &colliding);
+    if reserved == Err(ResponseStatus::PsaErrorInsufficientStorage) {
+        error!(
+            "No key ID free on the token found after {} attempts.",
+            KEY_ID_ATTEMPTS
+        );
+    }
+
+    Ok(ReservedKeyId {
+        local_ids,
+        reserved_ids,
+        key_id: reserved?,
+        committed: false,
+    })
+}
+
+/// Release key IDs reserved but not stored.
+fn release_key_ids(
+    local_ids: &RwLock<LocalIdStore>,
+    reserved_ids: &Mutex<HashSet<KeyId>>,
+    key_ids: &[KeyId],
+) {
+    if key_ids.is_empty() {
+        return;
+    }
+    let mut local_ids_handle = local_ids.write().expect("Local ID lock poisoned");
+    let mut reserved_ids_handle = reserved_ids.lock().expect("Reserved ID lock poisoned");
+    for key_id in key_ids {
+        let _ = local_ids_handle.remove(key_id);
+        let _ = reserved_ids_handle.remove(key_id);
+    }
+}
+
+/// Get a key ID which is not already in use from the allocator and reserve it.
+fn allocate_key_id(
+    allocator: &dyn KeyIdAllocator,
+    key_triple: &KeyTriple,
+    attributes: &Attributes,
+    width: usize,
+    local_ids: &mut LocalIdStore,
+) -> Result<KeyId> {
+    let key_id = allocator.propose(key_triple, attributes, width, local_ids);
+    if key_id.width() != width {
+        error!(
+            "The key ID allocator proposed an ID of {} bytes instead of {}.",
+            key_id.width(),
+            width
+        );
+        return Err(ResponseStatus::PsaErrorGenericError);
+    }
+    if !local_ids.insert(key_id) {
+        error!("The key ID allocator proposed an ID already in use.");
+        return Err(ResponseStatus::PsaErrorAlreadyExists);
+    }
+    Ok(key_id)
+}
+
+/// Compare the local key IDs with the ones referenced in the Key Info Manager. The `reserved`
+/// IDs are in use by keys being created: they are local but not stored yet.
+fn reconcile_key_ids(
+    local_ids: &mut LocalIdStore,
+    stored_ids: &HashSet<KeyId>,
+    reserved_ids: &HashSet<KeyId>,
+    mode: IdDiscrepancy,
+) -> Result<usize> {
+    let stale: Vec<KeyId> = local_ids
+        .iter()
+        .filter(|key_id| !stored_ids.contains(key_id) && !reserved_ids.contains(key_id))
+        .copied()
+        .collect();
+    let missing: Vec<KeyId> = stored_ids.difference(local_ids).copied().collect();
+    if stale.is_empty() && missing.is_empty() {
+        return Ok(0);
+    }
+
+    match mode {
+        IdDiscrepancy::Log => warn!(
+            "{} local key IDs are not in the Key Info Manager and {} stored key IDs are not local.",
+            stale.len(),
+            missing.len()
+        ),
+        IdDiscrepancy::Heal => {
+            warn!(
+                "Removing {} stale local key IDs and adding {} stored key IDs.",
+                stale.len(),
+                missing.len()
+            );
+            for key_id in &stale {
+                let _ = local_ids.remove(key_id);
+            }
+            local_ids.extend(missing.iter().copied());
+        }
+        IdDiscrepancy::Fail => {
+            error!(
+                "{} local key IDs are not in the Key Info Manager and {} stored key IDs are not local.",
+                stale.len(),
+                missing.len()
+            );
+            return Err(ResponseStatus::PsaErrorCorruptionDetected);
+        }
+    }
+
+    Ok(stale.len() + missing.len())
+}
+
+#[cfg(test)]
+fn sorted_key_ids(local_ids: &LocalIdStore) -> Vec<KeyId> {
+    let mut key_ids: Vec<KeyId> = local_ids.iter().copied().collect();
+    key_ids.sort_unstable();
+    key_ids
+}
+
+#[cfg(test)]
+fn local_ids_snapshot(local_ids: &RwLock<LocalIdStore>) -> Vec<KeyId> {
+    sorted_key_ids(&local_ids.read().expect("Local ID lock poisoned"))
+}
+
+#[cfg(test)]
+fn restore_local_ids(local_ids: &RwLock<LocalIdStore>, key_ids: &[KeyId]) {
+    *local_ids.write().expect("Local ID lock poisoned") = key_ids.iter().copied().collect();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        allocate_key_id, local_ids_snapshot, reconcile_key_ids, reserve_key_id, restore_local_ids,
+        sorted_key_ids, DeterministicKeyIdAllocator, IdDiscrepancy, KeyId, KeyIdAllocator,
+        LocalIdStore, RandomKeyIdAllocator, DETERMINISTIC_KEY_ID_SALTS, KEY_ID_ATTEMPTS,
+    };
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::test_utils::{test_client, test_key_attributes};
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::operations::psa_key_attributes::Attributes;
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::collections::HashSet;
+    use std::fs;
+    use std::sync::{Mutex, RwLock};
+
+    /// Allocates IDs sequentially from 1, moving to the next candidate when one is taken.
+    struct SequentialKeyIdAllocator;
+
+    impl KeyIdAllocator for SequentialKeyIdAllocator {
+        fn propose(
+            &self,
+            _key_triple: &KeyTriple,
+            _attributes: &Attributes,
+            _width: usize,
+            taken: &HashSet<KeyId>,
+        ) -> KeyId {
+            (1..)
+                .map(KeyId::from)
+                .find(|key_id| !taken.contains(key_id))
+                .unwrap()
+        }
+    }
+
+    /// Always proposes the same ID.
+    struct ConstantKeyIdAllocator;
+
+    impl KeyIdAllocator for ConstantKeyIdAllocator {
+        fn propose(
+            &self,
+            _key_triple: &KeyTriple,
+            _attributes: &Attributes,
+            _width: usize,
+            _taken: &HashSet<KeyId>,
+        ) -> KeyId {
+            KeyId::from(7)
+        }
+    }
+
+    fn test_key_triple() -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::from_name("test app".to_string()),
+            ProviderID::Pkcs11,
+            "test key".to_string(),
+        )
+    }
+
+    fn key_ids(key_ids: &[u32]) -> Vec<KeyId> {
+        key_ids.iter().copied().map(KeyId::from).collect()
+    }
+
+    fn id_store(key_ids: &[u32]) -> LocalIdStore {
+        key_ids.iter().copied().map(KeyId::from).collect()
+    }
+
+    fn allocate(allocator: &dyn KeyIdAllocator, local_ids: &mut LocalIdStore) -> KeyId {
+        allocate_wide(allocator, 4, local_ids)
+    }
+
+    fn allocate_wide(
+        allocator: &dyn KeyIdAllocator,
+        width: usize,
+        local_ids: &mut LocalIdStore,
+    ) -> KeyId {
+        allocate_key_id(
+            allocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            width,
+            local_ids,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn snapshot_contains_allocated_ids() {
+        let mut local_ids = LocalIdStore::new();
+        let mut allocated: Vec<KeyId> = (0..10)
+            .map(|_| allocate(&RandomKeyIdAllocator, &mut local_ids))
+            .collect();
+        allocated.sort_unstable();
+
+        assert_eq!(sorted_key_ids(&local_ids), allocated);
+    }
+
+    #[test]
+    fn allocation_skips_restored_ids() {
+        let mut local_ids = id_store(&[1, 2, 3]);
+        let key_id = allocate(&RandomKeyIdAllocator, &mut local_ids);
+
+        assert!(!key_ids(&[1, 2, 3]).contains(&key_id));
+        assert_eq!(sorted_key_ids(&local_ids).len(), 4);
+    }
+
+    #[test]
+    fn restored_ids_are_not_reserved_again() {
+        let local_ids = RwLock::new(id_store(&[9]));
+        let reserved_ids = Mutex::new(HashSet::new());
+        restore_local_ids(&local_ids, &key_ids(&[3, 1, 2]));
+        assert_eq!(local_ids_snapshot(&local_ids), key_ids(&[1, 2, 3]));
+
+        let reserved = reserve_key_id(
+            &SequentialKeyIdAllocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &local_ids,
+            &reserved_ids,
+            |_| Ok(false),
+        )
+        .unwrap();
+        assert_eq!(reserved.id(), KeyId::from(4));
+        assert_eq!(local_ids_snapshot(&local_ids), key_ids(&[1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn deterministic_key_ids_are_stable() {
+        let first = allocate(&DeterministicKeyIdAllocator, &mut LocalIdStore::new());
+        let second = allocate(&DeterministicKeyIdAllocator, &mut LocalIdStore::new());
+        assert_eq!(first, second);
+
+        let other_key = KeyTriple::new(
+            ApplicationName::from_name("test app".to_string()),
+            ProviderID::Pkcs11,
+            "other key".to_string(),
+        );
+        let other = DeterministicKeyIdAllocator.propose(
+            &other_key,
+            &test_key_attributes(),
+            4,
+            &HashSet::new(),
+        );
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn deterministic_key_id_collision_is_salted() {
+        let mut local_ids = LocalIdStore::new();
+        let first = allocate(&DeterministicKeyIdAllocator, &mut local_ids);
+
+        // The same key triple collides with the ID already in the store.
+        let second = allocate(&DeterministicKeyIdAllocator, &mut local_ids);
+        assert_ne!(first, second);
+        assert_eq!(
+            second,
+            DeterministicKeyIdAllocator::key_id(&test_key_triple(), 4, 1).unwrap()
+        );
+
+        // The salted ID is derived the same way every time.
+        let mut taken = HashSet::new();
+        let _ = taken.insert(first);
+        assert_eq!(
+            DeterministicKeyIdAllocator.propose(
+                &test_key_triple(),
+                &test_key_attributes(),
+                4,
+                &taken
+            ),
+            second
+        );
+    }
+
+    #[test]
+    fn deterministic_key_id_falls_back_to_random() {
+        psa_crypto::init().unwrap();
+        let salted: HashSet<KeyId> = (0..DETERMINISTIC_KEY_ID_SALTS)
+            .map(|salt| DeterministicKeyIdAllocator::key_id(&test_key_triple(), 4, salt).unwrap())
+            .collect();
+
+        let key_id = DeterministicKeyIdAllocator.propose(
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &salted,
+        );
+        assert!(!salted.contains(&key_id));
+        assert_eq!(key_id.as_bytes().len(), 4);
+    }
+
+    #[test]
+    fn wide_key_ids_are_allocated() {
+        for width in &[8, 16] {
+            let mut local_ids = id_store(&[1, 2]);
+            let random = allocate_wide(&RandomKeyIdAllocator, *width, &mut local_ids);
+            assert_eq!(random.width(), *width);
+
+            let deterministic = allocate_wide(
+                &DeterministicKeyIdAllocator,
+                *width,
+                &mut LocalIdStore::new(),
+            );
+            assert_eq!(deterministic.width(), *width);
+            assert_eq!(
+                allocate_wide(
+                    &DeterministicKeyIdAllocator,
+                    *width,
+                    &mut LocalIdStore::new()
+                ),
+                deterministic
+            );
+        }
+    }
+
+    #[test]
+    fn reject_key_id_of_other_width_from_allocator() {
+        let mut local_ids = LocalIdStore::new();
+        assert_eq!(
+            allocate_key_id(
+                &ConstantKeyIdAllocator,
+                &test_key_triple(),
+                &test_key_attributes(),
+                8,
+                &mut local_ids,
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorGenericError
+        );
+        assert!(local_ids.is_empty());
+    }
+
+    #[test]
+    fn custom_allocator_moves_to_next_candidate() {
+        let mut local_ids = id_store(&[2]);
+
+        assert_eq!(
+            allocate(&SequentialKeyIdAllocator, &mut local_ids),
+            KeyId::from(1)
+        );
+        assert_eq!(
+            allocate(&SequentialKeyIdAllocator, &mut local_ids),
+            KeyId::from(3)
+        );
+        assert_eq!(sorted_key_ids(&local_ids), key_ids(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn reject_taken_id_from_allocator() {
+        let mut local_ids = LocalIdStore::new();
+        assert_eq!(
+            allocate(&ConstantKeyIdAllocator, &mut local_ids),
+            KeyId::from(7)
+        );
+
+        assert_eq!(
+            allocate_key_id(
+                &ConstantKeyIdAllocator,
+                &test_key_triple(),
+                &test_key_attributes(),
+                4,
+                &mut local_ids,
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorAlreadyExists
+        );
+    }
+
+    #[test]
+    fn stale_local_id_is_logged() {
+        let stored_ids = id_store(&[1, 2]);
+        let mut local_ids = id_store(&[1, 2, 3]);
+
+        assert_eq!(
+            reconcile_key_ids(
+                &mut local_ids,
+                &stored_ids,
+                &HashSet::new(),
+                IdDiscrepancy::Log
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(sorted_key_ids(&local_ids), key_ids(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn stale_local_id_is_healed() {
+        let stored_ids = id_store(&[1, 2]);
+        let mut local_ids = id_store(&[1, 3]);
+
+        assert_eq!(
+            reconcile_key_ids(
+                &mut local_ids,
+                &stored_ids,
+                &HashSet::new(),
+                IdDiscrepancy::Heal
+            )
+            .unwrap(),
+            2
+        );
+        assert_eq!(sorted_key_ids(&local_ids), key_ids(&[1, 2]));
+    }
+
+    #[test]
+    fn stale_local_id_fails() {
+        let stored_ids = id_store(&[1, 2]);
+        let mut local_ids = id_store(&[1, 2, 3]);
+
+        assert_eq!(
+            reconcile_key_ids(
+                &mut local_ids,
+                &stored_ids,
+                &HashSet::new(),
+                IdDiscrepancy::Fail
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorCorruptionDetected
+        );
+        assert!(reconcile_key_ids(
+            &mut stored_ids.clone(),
+            &stored_ids,
+            &HashSet::new(),
+            IdDiscrepancy::Fail
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn reserved_key_id_is_not_stale() {
+        let stored_ids = id_store(&[1, 2]);
+        let reserved_ids = id_store(&[3]);
+
+        for mode in &[IdDiscrepancy::Heal, IdDiscrepancy::Fail] {
+            let mut local_ids = id_store(&[1, 2, 3]);
+            assert_eq!(
+                reconcile_key_ids(&mut local_ids, &stored_ids, &reserved_ids, *mode).unwrap(),
+                0
+            );
+            assert_eq!(sorted_key_ids(&local_ids), key_ids(&[1, 2, 3]));
+        }
+
+        // The reservation is kept while healing the other discrepancies.
+        let mut local_ids = id_store(&[1, 3, 4]);
+        assert_eq!(
+            reconcile_key_ids(
+                &mut local_ids,
+                &stored_ids,
+                &reserved_ids,
+                IdDiscrepancy::Heal
+            )
+            .unwrap(),
+            2
+        );
+        assert_eq!(sorted_key_ids(&local_ids), key_ids(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn uncommitted_key_id_is_released() {
+        let (client, path) = test_client("uncommitted_key_id");
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let reserved_ids = Mutex::new(HashSet::new());
+        {
+            let reserved = reserve_key_id(
+                &SequentialKeyIdAllocator,
+                &test_key_triple(),
+                &test_key_attributes(),
+                4,
+                &local_ids,
+                &reserved_ids,
+                |_| Ok(false),
+            )
+            .unwrap();
+            assert_eq!(reserved.id(), KeyId::from(1));
+            assert_eq!(sorted_key_ids(&local_ids.read().unwrap()), key_ids(&[1]));
+            // The key creation fails here.
+        }
+        assert!(local_ids.read().unwrap().is_empty());
+
+        let reserved = reserve_key_id(
+            &SequentialKeyIdAllocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &local_ids,
+            &reserved_ids,
+            |_| Ok(false),
+        )
+        .unwrap();
+        reserved
+            .commit(&client, test_key_triple(), test_key_attributes())
+            .unwrap();
+        assert_eq!(sorted_key_ids(&local_ids.read().unwrap()), key_ids(&[1]));
+        assert_eq!(client.get_key_id::<u32>(&test_key_triple()).unwrap(), 1);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn failed_commit_leaves_nothing() {
+        let (client, path) = test_client("failed_commit");
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let reserved_ids = Mutex::new(HashSet::new());
+        let reserved = reserve_key_id(
+            &SequentialKeyIdAllocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &local_ids,
+            &reserved_ids,
+            |_| Ok(false),
+        )
+        .unwrap();
+
+        // The mappings can no longer be written.
+        fs::remove_dir_all(&path).unwrap();
+        fs::write(&path, b"").unwrap();
+        assert!(reserved
+            .commit(&client, test_key_triple(), test_key_attributes())
+            .is_err());
+
+        assert!(local_ids.read().unwrap().is_empty());
+        assert!(client.get_all().unwrap().is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn key_id_on_token_is_skipped() {
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let reserved_ids = Mutex::new(HashSet::new());
+        let mut find_objects_calls = Vec::new();
+
+        // The token already has an object with the first candidate ID.
+        let reserved = reserve_key_id(
+            &SequentialKeyIdAllocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &local_ids,
+            &reserved_ids,
+            |key_id| {
+                find_objects_calls.push(key_id);
+                Ok(key_id == KeyId::from(1))
+            },
+        )
+        .unwrap();
+        assert_eq!(reserved.id(), KeyId::from(2));
+        assert_eq!(find_objects_calls, key_ids(&[1, 2]));
+        assert_eq!(sorted_key_ids(&local_ids.read().unwrap()), key_ids(&[2]));
+    }
+
+    #[test]
+    fn token_is_searched_without_local_id_lock() {
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let reserved_ids = Mutex::new(HashSet::new());
+
+        let reserved = reserve_key_id(
+            &SequentialKeyIdAllocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &local_ids,
+            &reserved_ids,
+            |key_id| {
+                // Other keys can be created or looked up while the token is searched.
+                let local_ids_handle = local_ids.try_write().unwrap();
+                assert!(local_ids_handle.contains(&key_id));
+                Ok(key_id == KeyId::from(1))
+            },
+        )
+        .unwrap();
+        assert_eq!(reserved.id(), KeyId::from(2));
+        assert_eq!(local_ids_snapshot(&local_ids), key_ids(&[2]));
+    }
+
+    #[test]
+    fn token_error_releases_key_id() {
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let reserved_ids = Mutex::new(HashSet::new());
+
+        let reserved = reserve_key_id(
+            &SequentialKeyIdAllocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &local_ids,
+            &reserved_ids,
+            |_| Err(ResponseStatus::PsaErrorCommunicationFailure),
+        );
+        assert_eq!(
+            reserved.unwrap_err(),
+            ResponseStatus::PsaErrorCommunicationFailure
+        );
+        assert!(local_ids.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn no_free_key_id_on_token() {
+        let local_ids = RwLock::new(LocalIdStore::new());
+        let reserved_ids = Mutex::new(HashSet::new());
+        let mut find_objects_calls = 0;
+
+        let reserved = reserve_key_id(
+            &SequentialKeyIdAllocator,
+            &test_key_triple(),
+            &test_key_attributes(),
+            4,
+            &local_ids,
+            &reserved_ids,
+            |_| {
+                find_objects_calls += 1;
+                Ok(true)
+            },
+        );
+        assert_eq!(
+            reserved.unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        assert_eq!(find_objects_calls, KEY_ID_ATTEMPTS);
+        assert!(local_ids.read().unwrap().is_empty());
     }
 }
@@ -0,0 +1,178 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Per-key count of the operations using a key, for the destruction of a key to wait for them.
+//!
+//! Lock discipline: the lock of the counts is never held while calling the Key Info Manager or
+//! the token. Operations take their guard once they have read the key ID from the Key Info
+//! Manager, and the destruction of a key waits for the other operations before touching the Key
+//! Info Manager or the token. Waiting only ever happens in `destroying`, on operations which do
+//! not wait themselves.
+use super::KeyId;
+use log::error;
+use parsec_interface::requests::{ResponseStatus, Result};
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+#[derive(Debug, Default)]
+pub(super) struct KeyUsers {
+    keys: Mutex<HashMap<KeyId, KeyUse>>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct KeyUse {
+    users: usize,
+    destroying: bool,
+}
+
+/// Marks a key as used by an operation until dropped.
+#[derive(Debug)]
+pub(super) struct KeyUseGuard<'a> {
+    key_users: &'a KeyUsers,
+    key_id: KeyId,
+}
+
+/// Marks a key as being destroyed until dropped.
+#[derive(Debug)]
+pub(super) struct KeyDestroyGuard<'a> {
+    key_users: &'a KeyUsers,
+    key_id: KeyId,
+}
+
+impl KeyUsers {
+    /// Register an operation using the key, unless it is being destroyed.
+    pub(super) fn acquire(&self, key_id: KeyId) -> Result<KeyUseGuard<'_>> {
+        let mut keys = self.keys.lock().expect("Key users lock poisoned");
+        let key_use = keys.entry(key_id).or_default();
+        if key_use.destroying {
+            error!("The key is being destroyed.");
+            return Err(ResponseStatus::PsaErrorDoesNotExist);
+        }
+        key_use.users += 1;
+
+        Ok(KeyUseGuard {
+            key_users: self,
+            key_id,
+        })
+    }
+
+    /// Refuse new operations using the key and wait for the ones using it to finish. Another
+    /// destruction of the same key in progress is waited for first.
+    pub(super) fn destroying(&self, key_id: KeyId) -> KeyDestroyGuard<'_> {
+        let keys = self.keys.lock().expect("Key users lock poisoned");
+        let mut keys = self
+            .released
+            .wait_while(keys, |keys| {
+                keys.get(&key_id)
+                    .map_or(false, |key_use| key_use.destroying)
+            })
+            .expect("Key users lock poisoned");
+        keys.entry(key_id).or_default().destroying = true;
+        let _keys = self
+            .released
+            .wait_while(keys, |keys| {
+                keys.get(&key_id).map_or(false, |key_use| key_use.users > 0)
+            })
+            .expect("Key users lock poisoned");
+
+        KeyDestroyGuard {
+            key_users: self,
+            key_id,
+        }
+    }
+}
+
+impl Drop for KeyUseGuard<'_> {
+    fn drop(&mut self) {
+        let mut keys = self.key_users.keys.lock().expect("Key users lock poisoned");
+        if let Some(key_use) = keys.get_mut(&self.key_id) {
+            key_use.users -= 1;
+            if key_use.users == 0 {
+                if !key_use.destroying {
+                    let _ = keys.remove(&self.key_id);
+                }
+                self.key_users.released.notify_all();
+            }
+        }
+    }
+}
+
+impl Drop for KeyDestroyGuard<'_> {
+    fn drop(&mut self) {
+        let mut keys = self.key_users.keys.lock().expect("Key users lock poisoned");
+        let _ = keys.remove(&self.key_id);
+        self.key_users.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyUsers;
+    use crate::providers::pkcs11::KeyId;
+    use parsec_interface::requests::ResponseStatus;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn destroy_waits_for_signing() {
+        let key_users = Arc::new(KeyUsers::default());
+        let destroyed = Arc::new(AtomicBool::new(false));
+        let (started_tx, started_rx) = mpsc::channel();
+        let (finish_tx, finish_rx) = mpsc::channel::<()>();
+
+        let signing_users = key_users.clone();
+        let signing_destroyed = destroyed.clone();
+        let sign = thread::spawn(move || {
+            let _guard = signing_users.acquire(KeyId::from(1)).unwrap();
+            started_tx.send(()).unwrap();
+            finish_rx.recv().unwrap();
+            // The key objects are still there while signing.
+            assert!(!signing_destroyed.load(Ordering::SeqCst));
+        });
+        started_rx.recv().unwrap();
+
+        let destroying_users = key_users.clone();
+        let destroying_destroyed = destroyed.clone();
+        let destroy = thread::spawn(move || {
+            let _guard = destroying_users.destroying(KeyId::from(1));
+            destroying_destroyed.store(true, Ordering::SeqCst);
+        });
+
+        // Wait for the destruction to start before trying a new operation.
+        while !key_users
+            .keys
+            .lock()
+            .unwrap()
+            .get(&KeyId::from(1))
+            .map_or(false, |key_use| key_use.destroying)
+        {
+            thread::yield_now();
+        }
+        assert_eq!(
+            key_users.acquire(KeyId::from(1)).unwrap_err(),
+            ResponseStatus::PsaErrorDoesNotExist
+        );
+        // Other keys are not affected.
+        let _other = key_users.acquire(KeyId::from(2)).unwrap();
+
+        finish_tx.send(()).unwrap();
+        sign.join().unwrap();
+        destroy.join().unwrap();
+        assert!(destroyed.load(Ordering::SeqCst));
+        assert!(key_users.acquire(KeyId::from(1)).is_ok());
+    }
+
+    #[test]
+    fn unused_keys_are_forgotten() {
+        let key_users = KeyUsers::default();
+        {
+            let _first = key_users.acquire(KeyId::from(1)).unwrap();
+            let _second = key_users.acquire(KeyId::from(1)).unwrap();
+        }
+        drop(key_users.destroying(KeyId::from(2)));
+
+        assert!(key_users.keys.lock().unwrap().is_empty());
+    }
+}
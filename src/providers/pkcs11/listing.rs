@@ -0,0 +1,114 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::{key_id, KeyId, KeyPairType, Provider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
+use parsec_interface::operations::psa_key_attributes::Attributes;
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+
+/// Key of an application, with whether its objects are still on the token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedKey {
+    /// Name of the key
+    pub name: String,
+    /// Attributes of the key, as stored in the Key Info Manager
+    pub attributes: Attributes,
+    /// Whether an object with the key ID was found on the token
+    pub on_token: bool,
+}
+
+impl Provider {
+    /// List the keys of an application stored in this provider, checking for each of them that
+    /// its objects are still on the token.
+    ///
+    /// Only the keys of `app_name` are listed.
+    pub fn list_keys_on_token(&self, app_name: ApplicationName) -> Result<Vec<ListedKey>> {
+        let keys = app_keys(&self.key_info_store, &app_name)?;
+        let session = self.new_read_only_session()?;
+
+        let mut listed = Vec::new();
+        for (name, key_id, attributes) in keys {
+            let on_token = match self.find_key(&session, key_id, KeyPairType::Any) {
+                Ok(_) => true,
+                Err(ResponseStatus::PsaErrorDoesNotExist) => false,
+                Err(e) => return Err(e),
+            };
+            listed.push(ListedKey {
+                name,
+                attributes,
+                on_token,
+            });
+        }
+
+        Ok(listed)
+    }
+}
+
+/// Get the name, key ID and attributes of the keys of an application.
+fn app_keys(
+    key_info_store: &KeyInfoManagerClient,
+    app_name: &ApplicationName,
+) -> Result<Vec<(String, KeyId, Attributes)>> {
+    let mut keys = Vec::new();
+    for key_info in key_info_store.list_keys(app_name)? {
+        let key_triple = KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key_info.name);
+        let key_id = key_id::stored_key_id(key_info_store, &key_triple)?;
+        keys.push((
+            key_triple.key_name().to_string(),
+            key_id,
+            key_info.attributes,
+        ));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use super::app_keys;
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::test_utils::{test_client, test_key_attributes};
+    use crate::key_info_managers::KeyTriple;
+    use crate::providers::pkcs11::KeyId;
+    use parsec_interface::requests::ProviderID;
+    use std::fs;
+
+    #[test]
+    fn applications_only_list_their_keys() {
+        let (client, path) = test_client("app_keys");
+
+        let alice = ApplicationName::from_name("alice".to_string());
+        let bob = ApplicationName::from_name("bob".to_string());
+        for (app_name, key_name, key_id) in vec![
+            (&alice, "key 1", 1u32),
+            (&alice, "key 2", 2),
+            (&bob, "key 1", 3),
+        ] {
+            let key_triple =
+                KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key_name.to_string());
+            client
+                .insert_key_info(key_triple, &key_id, test_key_attributes())
+                .unwrap();
+        }
+
+        let mut alice_keys = app_keys(&client, &alice).unwrap();
+        alice_keys.sort_by_key(|(_, key_id, _)| *key_id);
+        assert_eq!(
+            alice_keys,
+            vec![
+                ("key 1".to_string(), KeyId::from(1), test_key_attributes()),
+                ("key 2".to_string(), KeyId::from(2), test_key_attributes()),
+            ]
+        );
+        assert_eq!(
+            app_keys(&client, &bob).unwrap(),
+            vec![("key 1".to_string(), KeyId::from(3), test_key_attributes())]
+        );
+        assert!(
+            app_keys(&client, &ApplicationName::from_name("eve".to_string()))
+                .unwrap()
+                .is_empty()
+        );
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
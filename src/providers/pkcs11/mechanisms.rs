@@ -0,0 +1,195 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::utils::to_response_status;
+use super::{KeyPairType, Provider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use cryptoki::types::mechanism::{Mechanism, MechanismType};
+use cryptoki::types::object::{Attribute, AttributeType, ObjectHandle};
+use cryptoki::types::session::Session;
+use cryptoki::Error;
+use log::{info, trace, warn};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use std::convert::TryFrom;
+
+/// Mechanisms the token allows for the objects of one key, compared with its stored policy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMechanisms {
+    /// Name of the key
+    pub key_name: String,
+    /// Names of the mechanisms in the `CKA_ALLOWED_MECHANISMS` of the key objects
+    pub token_mechanisms: Vec<String>,
+    /// Whether the token allows mechanisms that the stored policy does not permit
+    pub exceeds_policy: bool,
+}
+
+/// Mechanisms allowed for one object of a key.
+#[derive(Debug)]
+enum ObjectMechanisms {
+    /// Only the listed mechanisms are allowed.
+    Restricted(Vec<MechanismType>),
+    /// The list contains mechanisms the PKCS 11 crate can not decode.
+    Undecodable,
+    /// The object has no `CKA_ALLOWED_MECHANISMS`: all mechanisms are allowed.
+    Unrestricted,
+}
+
+impl Provider {
+    /// Read the `CKA_ALLOWED_MECHANISMS` of the objects of each key of an application and
+    /// compare them with the algorithm permitted by the key policy.
+    ///
+    /// Keys whose objects allow more than the permitted algorithm are flagged. Nothing is
+    /// modified on the token.
+    pub fn audit_allowed_mechanisms(
+        &self,
+        app_name: ApplicationName,
+    ) -> Result<Vec<KeyMechanisms>> {
+        let session = self.new_session()?;
+
+        let mut report = Vec::new();
+        for key_info in self.key_info_store.list_keys(&app_name)? {
+            let key_triple =
+                KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key_info.name.clone());
+            let key_id = self.get_key_id(&key_triple)?;
+
+            let mut objects = Vec::new();
+            for key_type in vec![KeyPairType::PublicKey, KeyPairType::PrivateKey] {
+                match self.find_key(&session, key_id, key_type) {
+                    Ok(object) => objects.push(allowed_mechanisms(&session, object)?),
+                    // Public keys imported alone have no private part.
+                    Err(ResponseStatus::PsaErrorDoesNotExist) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            let permitted: Vec<MechanismType> =
+                Mechanism::try_from(key_info.attributes.policy.permitted_algorithms)
+                    .map(|mechanism| vec![mechanism.mechanism_type()])
+                    .unwrap_or_default();
+
+            report.push(compare_mechanisms(key_info.name, &objects, &permitted));
+        }
+        info!(
+            "{} keys audited, {} allowing more mechanisms than their policy.",
+            report.len(),
+            report.iter().filter(|key| key.exceeds_policy).count()
+        );
+
+        Ok(report)
+    }
+}
+
+fn allowed_mechanisms(session: &Session, object: ObjectHandle) -> Result<ObjectMechanisms> {
+    trace!("GetAttributeValue command");
+    match session.get_attributes(object, &[AttributeType::AllowedMechanisms]) {
+        Ok(attributes) => match attributes.as_slice() {
+            [Attribute::AllowedMechanisms(mechanisms)] => {
+                Ok(ObjectMechanisms::Restricted(mechanisms.clone()))
+            }
+            _ => Ok(ObjectMechanisms::Unrestricted),
+        },
+        Err(Error::NotSupported) => Ok(ObjectMechanisms::Undecodable),
+        Err(e) => Err(to_response_status(e)),
+    }
+}
+
+/// Compare the mechanisms allowed for the objects of a key with the permitted ones.
+fn compare_mechanisms(
+    key_name: String,
+    objects: &[ObjectMechanisms],
+    permitted: &[MechanismType],
+) -> KeyMechanisms {
+    let mut token_mechanisms = Vec::new();
+    let mut exceeds_policy = false;
+    for object in objects {
+        match object {
+            ObjectMechanisms::Restricted(mechanisms) => {
+                for mechanism in mechanisms {
+                    let name = mechanism_name(*mechanism).to_string();
+                    if !token_mechanisms.contains(&name) {
+                        token_mechanisms.push(name);
+                    }
+                    exceeds_policy |= !permitted.contains(mechanism);
+                }
+            }
+            ObjectMechanisms::Undecodable => {
+                warn!("Key object allowing mechanisms unknown to Parsec.");
+                exceeds_policy = true;
+            }
+            ObjectMechanisms::Unrestricted => {
+                warn!("Key object allowing all mechanisms.");
+                exceeds_policy = true;
+            }
+        }
+    }
+
+    KeyMechanisms {
+        key_name,
+        token_mechanisms,
+        exceeds_policy,
+    }
+}
+
+fn mechanism_name(mechanism: MechanismType) -> &'static str {
+    match mechanism {
+        MechanismType::RSA_PKCS_KEY_PAIR_GEN => "RSA_PKCS_KEY_PAIR_GEN",
+        MechanismType::RSA_PKCS => "RSA_PKCS",
+        MechanismType::RSA_PKCS_PSS => "RSA_PKCS_PSS",
+        MechanismType::RSA_PKCS_OAEP => "RSA_PKCS_OAEP",
+        MechanismType::SHA1 => "SHA_1",
+        MechanismType::SHA256 => "SHA256",
+        MechanismType::SHA384 => "SHA384",
+        MechanismType::SHA512 => "SHA512",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare_mechanisms, ObjectMechanisms};
+    use cryptoki::types::mechanism::MechanismType;
+
+    #[test]
+    fn broader_token_mechanisms_are_flagged() {
+        let permitted = [MechanismType::RSA_PKCS];
+
+        let narrow = compare_mechanisms(
+            "narrow".to_string(),
+            &[
+                ObjectMechanisms::Restricted(vec![MechanismType::RSA_PKCS]),
+                ObjectMechanisms::Restricted(vec![MechanismType::RSA_PKCS]),
+            ],
+            &permitted,
+        );
+        assert!(!narrow.exceeds_policy);
+        assert_eq!(narrow.token_mechanisms, vec!["RSA_PKCS".to_string()]);
+
+        let broad = compare_mechanisms(
+            "broad".to_string(),
+            &[
+                ObjectMechanisms::Restricted(vec![MechanismType::RSA_PKCS]),
+                ObjectMechanisms::Restricted(vec![
+                    MechanismType::RSA_PKCS,
+                    MechanismType::RSA_PKCS_PSS,
+                ]),
+            ],
+            &permitted,
+        );
+        assert!(broad.exceeds_policy);
+        assert_eq!(
+            broad.token_mechanisms,
+            vec!["RSA_PKCS".to_string(), "RSA_PKCS_PSS".to_string()]
+        );
+    }
+
+    #[test]
+    fn unrestricted_objects_are_flagged() {
+        let permitted = [MechanismType::RSA_PKCS];
+
+        for object in vec![
+            ObjectMechanisms::Unrestricted,
+            ObjectMechanisms::Undecodable,
+        ] {
+            assert!(compare_mechanisms("key".to_string(), &[object], &permitted).exceeds_policy);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::Provider;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts of the operations done by the provider since it started.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Pkcs11Metrics {
+    /// Keys generated
+    pub generated_keys: u64,
+    /// Keys imported
+    pub imported_keys: u64,
+    /// Public keys exported
+    pub exported_keys: u64,
+    /// Keys destroyed
+    pub destroyed_keys: u64,
+    /// Key objects found in the handle cache
+    pub find_hits: u64,
+    /// Key objects searched on the token
+    pub find_misses: u64,
+    /// Sessions opened on the token
+    pub session_opens: u64,
+}
+
+/// Operation counted in the metrics of the provider.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) enum Counter {
+    GeneratedKey,
+    ImportedKey,
+    ExportedKey,
+    DestroyedKey,
+    FindHit,
+    FindMiss,
+    SessionOpen,
+}
+
+/// Lock-free counters behind the metrics of the provider.
+#[derive(Debug, Default)]
+pub(super) struct OperationCounters {
+    generated_keys: AtomicU64,
+    imported_keys: AtomicU64,
+    exported_keys: AtomicU64,
+    destroyed_keys: AtomicU64,
+    find_hits: AtomicU64,
+    find_misses: AtomicU64,
+    session_opens: AtomicU64,
+}
+
+impl OperationCounters {
+    /// Count one more operation.
+    pub(super) fn increment(&self, counter: Counter) {
+        let counter = match counter {
+            Counter::GeneratedKey => &self.generated_keys,
+            Counter::ImportedKey => &self.imported_keys,
+            Counter::ExportedKey => &self.exported_keys,
+            Counter::DestroyedKey => &self.destroyed_keys,
+            Counter::FindHit => &self.find_hits,
+            Counter::FindMiss => &self.find_misses,
+            Counter::SessionOpen => &self.session_opens,
+        };
+        let _ = counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Read the current value of the counters.
+    pub(super) fn snapshot(&self) -> Pkcs11Metrics {
+        Pkcs11Metrics {
+            generated_keys: self.generated_keys.load(Ordering::Relaxed),
+            imported_keys: self.imported_keys.load(Ordering::Relaxed),
+            exported_keys: self.exported_keys.load(Ordering::Relaxed),
+            destroyed_keys: self.destroyed_keys.load(Ordering::Relaxed),
+            find_hits: self.find_hits.load(Ordering::Relaxed),
+            find_misses: self.find_misses.load(Ordering::Relaxed),
+            session_opens: self.session_opens.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Provider {
+    /// Get the counts of the operations done by the provider since it started.
+    ///
+    /// Only the operations which succeeded are counted, except for key objects searched on the
+    /// token which are counted whether they were found or not.
+    pub fn metrics(&self) -> Pkcs11Metrics {
+        self.counters.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Counter, OperationCounters, Pkcs11Metrics};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn operations_are_counted() {
+        let counters = OperationCounters::default();
+        assert_eq!(counters.snapshot(), Pkcs11Metrics::default());
+
+        counters.increment(Counter::SessionOpen);
+        counters.increment(Counter::FindMiss);
+        counters.increment(Counter::GeneratedKey);
+        counters.increment(Counter::SessionOpen);
+        counters.increment(Counter::FindHit);
+        counters.increment(Counter::ExportedKey);
+        counters.increment(Counter::SessionOpen);
+        counters.increment(Counter::FindHit);
+        counters.increment(Counter::DestroyedKey);
+
+        assert_eq!(
+            counters.snapshot(),
+            Pkcs11Metrics {
+                generated_keys: 1,
+                imported_keys: 0,
+                exported_keys: 1,
+                destroyed_keys: 1,
+                find_hits: 2,
+                find_misses: 1,
+                session_opens: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn concurrent_operations_are_counted() {
+        let counters = Arc::new(OperationCounters::default());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let counters = counters.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counters.increment(Counter::ImportedKey);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(counters.snapshot().imported_keys, 8000);
+    }
+}
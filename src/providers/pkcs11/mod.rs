@@ -8,12 +8,18 @@ use super::Provide;
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::{KeyInfoManagerClient, KeyTriple};
 use cryptoki::types::locking::CInitializeArgs;
+use cryptoki::types::object::ObjectHandle;
 use cryptoki::types::session::{Session, UserType};
 use cryptoki::types::slot_token::Slot;
 use cryptoki::types::Flags;
 use cryptoki::Pkcs11;
 use derivative::Derivative;
+use fingerprint::FingerprintCache;
+use handle_cache::HandleCache;
+use key_users::KeyUsers;
 use log::{error, info, trace, warn};
+use metrics::{Counter, OperationCounters};
+use parsec_interface::operations::psa_algorithm::Hash;
 use parsec_interface::operations::{list_clients, list_keys, list_providers::ProviderInfo};
 use parsec_interface::operations::{
     psa_asymmetric_decrypt, psa_asymmetric_encrypt, psa_destroy_key, psa_export_public_key,
@@ -21,22 +27,202 @@ use parsec_interface::operations::{
 };
 use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus, Result};
 use parsec_interface::secrecy::{ExposeSecret, SecretString};
+use sign_replay::SignedHashes;
 use std::collections::HashSet;
 use std::convert::TryInto;
+use std::fs;
 use std::io::{Error, ErrorKind};
 use std::str::FromStr;
-use std::sync::RwLock;
-use utils::{to_response_status, KeyPairType};
+use std::sync::{Mutex, RwLock};
+use utils::{to_response_status, KeyNameRules, KeyPairType};
 use uuid::Uuid;
+use verify_cache::VerifyCache;
 use zeroize::Zeroize;
 
-type LocalIdStore = HashSet<u32>;
+pub use catalog::CatalogEntry;
+pub use health::KeyHealth;
+pub use key_id::KeyId;
+pub use key_management::{ExpectedKey, ObjectToDestroy, RsaPublicIntegers};
+pub use key_metadata::{DeterministicKeyIdAllocator, KeyIdAllocator, RandomKeyIdAllocator};
+pub use listing::ListedKey;
+pub use mechanisms::KeyMechanisms;
+pub use metrics::Pkcs11Metrics;
+pub use warnings::{OperationWarning, Warnings};
+
+type LocalIdStore = HashSet<KeyId>;
+
+/// What to do at startup with Key Info Manager entries which do not have corresponding objects
+/// in the PKCS 11 token.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ConsistencyCheck {
+    /// Do not look for the keys in the token.
+    Off,
+    /// Log the mismatches but keep the entries.
+    Warn,
+    /// Log the mismatches and delete the entries.
+    Delete,
+    /// Abort the provider initialisation on the first mismatch.
+    Fail,
+}
+
+impl FromStr for ConsistencyCheck {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> std::io::Result<Self> {
+        match mode {
+            "off" => Ok(ConsistencyCheck::Off),
+            "warn" => Ok(ConsistencyCheck::Warn),
+            "delete" => Ok(ConsistencyCheck::Delete),
+            "fail" => Ok(ConsistencyCheck::Fail),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid startup consistency check mode \'{}\'", mode),
+            )),
+        }
+    }
+}
+
+/// What to do when the local key IDs and the ones referenced in the Key Info Manager disagree.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum IdDiscrepancy {
+    /// Log the discrepancies.
+    Log,
+    /// Log the discrepancies and make the local IDs match the Key Info Manager.
+    Heal,
+    /// Fail on discrepancies.
+    Fail,
+}
+
+impl FromStr for IdDiscrepancy {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> std::io::Result<Self> {
+        match mode {
+            "log" => Ok(IdDiscrepancy::Log),
+            "heal" => Ok(IdDiscrepancy::Heal),
+            "fail" => Ok(IdDiscrepancy::Fail),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid key ID discrepancy mode \'{}\'", mode),
+            )),
+        }
+    }
+}
+
+/// What to do when no user pin is set. The PKCS 11 crate does not expose the flags of the token
+/// so whether users must log in to it is part of the configuration.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum MissingUserPin {
+    /// Do not log the sessions in.
+    NoLogin,
+    /// Refuse to start the provider.
+    Fail,
+    /// Do not log the sessions in and only allow the operations using public keys.
+    PublicOnly,
+}
+
+impl FromStr for MissingUserPin {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> std::io::Result<Self> {
+        match mode {
+            "no-login" => Ok(MissingUserPin::NoLogin),
+            "fail" => Ok(MissingUserPin::Fail),
+            "public-only" => Ok(MissingUserPin::PublicOnly),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid missing user pin mode \'{}\'", mode),
+            )),
+        }
+    }
+}
+
+/// Get the user pin set in the configuration or read it from the environment variable or the file
+/// given instead. At most one of them can be set.
+fn resolve_user_pin(
+    user_pin: Option<SecretString>,
+    user_pin_env: Option<&str>,
+    user_pin_file: Option<&str>,
+) -> std::io::Result<Option<SecretString>> {
+    let mut pin = match (user_pin, user_pin_env, user_pin_file) {
+        (user_pin, None, None) => return Ok(user_pin),
+        (None, Some(var), None) => std::env::var(var).map_err(|_| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("user pin environment variable \'{}\' is not set", var),
+            )
+        })?,
+        (None, None, Some(path)) => fs::read_to_string(path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("cannot read the user pin file \'{}\': {}", path, e),
+            )
+        })?,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "only one of user_pin, user_pin_env and user_pin_file can be set",
+            ))
+        }
+    };
+    // The conversion form a String is infallible.
+    let user_pin = SecretString::from_str(pin.trim_end_matches(&['\r', '\n'][..])).unwrap();
+    pin.zeroize();
+
+    Ok(Some(user_pin))
+}
+
+/// Check that the provider can start with or without a user pin. Returns whether only the
+/// operations using public keys are allowed.
+fn check_user_pin(pin_set: bool, missing_user_pin: MissingUserPin) -> std::io::Result<bool> {
+    if pin_set {
+        return Ok(false);
+    }
+    match missing_user_pin {
+        MissingUserPin::NoLogin => Ok(false),
+        MissingUserPin::Fail => Err(Error::new(
+            ErrorKind::InvalidData,
+            "missing user pin, required to log in to the token",
+        )),
+        MissingUserPin::PublicOnly => {
+            warn!("No user pin set, only the operations using public keys are allowed.");
+            Ok(true)
+        }
+    }
+}
+
+/// Refuse to modify the token if the provider is read-only.
+fn check_writes_allowed(read_only: bool) -> Result<()> {
+    if read_only {
+        error!("The provider is read-only, the token can not be modified.");
+        return Err(ResponseStatus::PsaErrorNotPermitted);
+    }
+    Ok(())
+}
+
+/// Whether a session opened for an operation is read-write. A read-only provider only opens
+/// read-only sessions.
+fn session_read_write(read_write: bool, read_only: bool) -> bool {
+    read_write && !read_only
+}
 
 mod asym_encryption;
 mod asym_sign;
+mod catalog;
+mod fingerprint;
+mod handle_cache;
+mod health;
+mod key_id;
 mod key_management;
 mod key_metadata;
+mod key_users;
+mod listing;
+mod mechanisms;
+mod metrics;
+mod sign_replay;
 mod utils;
+mod verify_cache;
+mod warnings;
 
 const SUPPORTED_OPCODES: [Opcode; 8] = [
     Opcode::PsaGenerateKey,
@@ -60,16 +246,42 @@ pub struct Provider {
     #[derivative(Debug = "ignore")]
     key_info_store: KeyInfoManagerClient,
     local_ids: RwLock<LocalIdStore>,
+    // IDs reserved for keys being created, not stored in the Key Info Manager yet.
+    reserved_ids: Mutex<HashSet<KeyId>>,
+    // IDs of the key objects on the token which no mapping references, skipped for new keys.
+    unmapped_ids: RwLock<HashSet<KeyId>>,
     #[derivative(Debug = "ignore")]
     backend: Pkcs11,
     slot_number: Slot,
     software_public_operations: bool,
+    public_exponent: Vec<u8>,
+    check_imported_keys: bool,
+    disallowed_hashes: Vec<Hash>,
+    #[derivative(Debug = "ignore")]
+    key_id_allocator: Box<dyn KeyIdAllocator>,
+    id_discrepancy: IdDiscrepancy,
+    verify_cache: VerifyCache,
+    key_name_rules: KeyNameRules,
+    signed_hashes: SignedHashes,
+    handle_cache: HandleCache<ObjectHandle>,
+    check_generated_key_ids: bool,
+    login: bool,
+    public_only: bool,
+    relogin: bool,
+    check_key_types: bool,
+    fingerprint_cache: FingerprintCache,
+    key_users: KeyUsers,
+    key_id_width: usize,
+    read_only: bool,
+    counters: OperationCounters,
 }
 
 impl Provider {
     /// Creates and initialise a new instance of Pkcs11Provider.
-    /// Checks if there are not more keys stored in the Key Info Manager than in the PKCS 11 library
-    /// and if there are, delete them. Adds Key IDs currently in use in the local IDs store.
+    /// Depending on `consistency_check`, checks if there are not more keys stored in the Key Info
+    /// Manager than in the PKCS 11 library and if there are, warn about them, delete them or fail.
+    /// The keys are looked up on the token by `consistency_check_threads` threads.
+    /// Adds Key IDs currently in use in the local IDs store.
     /// Returns `None` if the initialisation failed.
     fn new(
         key_info_store: KeyInfoManagerClient,
@@ -77,7 +289,24 @@ impl Provider {
         slot_number: Slot,
         user_pin: Option<SecretString>,
         software_public_operations: bool,
+        public_exponent: Vec<u8>,
+        check_imported_keys: bool,
+        disallowed_hashes: Vec<Hash>,
+        key_id_allocator: Box<dyn KeyIdAllocator>,
+        consistency_check: ConsistencyCheck,
+        consistency_check_threads: usize,
+        id_discrepancy: IdDiscrepancy,
+        verify_cache_size: usize,
+        key_name_rules: KeyNameRules,
+        sign_replay_window: usize,
+        check_generated_key_ids: bool,
+        public_only: bool,
+        relogin: bool,
+        check_key_types: bool,
+        key_id_width: usize,
+        read_only: bool,
     ) -> Option<Provider> {
+        let login = user_pin.is_some();
         if let Some(pin) = user_pin {
             backend.set_pin(slot_number, pin.expose_secret()).ok()?;
         }
@@ -86,57 +315,105 @@ impl Provider {
         let pkcs11_provider = Provider {
             key_info_store,
             local_ids: RwLock::new(HashSet::new()),
+            reserved_ids: Mutex::new(HashSet::new()),
+            unmapped_ids: RwLock::new(HashSet::new()),
             backend,
             slot_number,
             software_public_operations,
+            public_exponent,
+            check_imported_keys,
+            disallowed_hashes,
+            key_id_allocator,
+            id_discrepancy,
+            verify_cache: VerifyCache::new(verify_cache_size),
+            key_name_rules,
+            signed_hashes: SignedHashes::new(sign_replay_window),
+            handle_cache: HandleCache::default(),
+            check_generated_key_ids,
+            login,
+            public_only,
+            relogin,
+            check_key_types,
+            fingerprint_cache: FingerprintCache::default(),
+            key_users: KeyUsers::default(),
+            key_id_width,
+            read_only,
+            counters: OperationCounters::default(),
         };
-        {
-            let mut local_ids_handle = pkcs11_provider
-                .local_ids
-                .write()
-                .expect("Local ID lock poisoned");
-            let mut to_remove: Vec<KeyTriple> = Vec::new();
-            // Go through all PKCS 11 key triple to key info mappings and check if they are still
-            // present.
-            // Delete those who are not present and add to the local_store the ones present.
-            match pkcs11_provider.key_info_store.get_all() {
-                Ok(key_triples) => {
-                    let session = pkcs11_provider.new_session().ok()?;
-
-                    for key_triple in key_triples.iter().cloned() {
-                        let key_id = match pkcs11_provider.key_info_store.get_key_id(&key_triple) {
-                            Ok(id) => id,
-                            Err(ResponseStatus::PsaErrorDoesNotExist) => {
-                                error!("Stored key info missing for key triple {}.", key_triple);
-                                continue;
+        let mut present_ids: Vec<KeyId> = Vec::new();
+        let mut to_remove: Vec<KeyTriple> = Vec::new();
+        // Go through all PKCS 11 key triple to key info mappings and check if they are still
+        // present.
+        // Handle those who are not present depending on the consistency check mode and add to
+        // the local_store the ones present.
+        match pkcs11_provider.key_info_store.get_all() {
+            Ok(key_triples) => {
+                let stored_keys =
+                    key_id::stored_key_ids(&pkcs11_provider.key_info_store, &key_triples);
+
+                if consistency_check == ConsistencyCheck::Off {
+                    present_ids.extend(stored_keys.iter().map(|(_, key_id)| *key_id));
+                } else {
+                    let key_ids: Vec<KeyId> =
+                        stored_keys.iter().map(|(_, key_id)| *key_id).collect();
+                    let found = match utils::parallel_with_sessions(
+                        &key_ids,
+                        consistency_check_threads,
+                        || pkcs11_provider.new_session(),
+                        |session, key_id| match pkcs11_provider.find_key(
+                            session,
+                            *key_id,
+                            KeyPairType::Any,
+                        ) {
+                            Ok(_) => Ok(true),
+                            Err(ResponseStatus::PsaErrorDoesNotExist) => Ok(false),
+                            Err(e) => Err(e),
+                        },
+                    ) {
+                        Ok(found) => found,
+                        Err(e) => {
+                            format_error!("Error finding key objects", e);
+                            return None;
+                        }
+                    };
+
+                    for ((key_triple, key_id), found) in stored_keys.into_iter().zip(found) {
+                        if found {
+                            if crate::utils::GlobalConfig::log_error_details() {
+                                warn!(
+                                    "Key {} found in the PKCS 11 library, adding it.",
+                                    key_triple
+                                );
+                            } else {
+                                warn!("Key found in the PKCS 11 library, adding it.");
                             }
-                            Err(e) => {
-                                format_error!(
-                                    format!(
-                                        "Stored key info invalid for key triple {}.",
+                            present_ids.push(key_id);
+                            continue;
+                        }
+                        match consistency_check {
+                            ConsistencyCheck::Fail => {
+                                if crate::utils::GlobalConfig::log_error_details() {
+                                    error!(
+                                        "Key {} not found in the PKCS 11 library, failing the startup.",
                                         key_triple
-                                    ),
-                                    e
-                                );
-
-                                to_remove.push(key_triple.clone());
-                                continue;
+                                    );
+                                } else {
+                                    error!("Key not found in the PKCS 11 library, failing the startup.");
+                                }
+                                return None;
                             }
-                        };
-
-                        match pkcs11_provider.find_key(&session, key_id, KeyPairType::Any) {
-                            Ok(_) => {
+                            ConsistencyCheck::Warn => {
                                 if crate::utils::GlobalConfig::log_error_details() {
                                     warn!(
-                                        "Key {} found in the PKCS 11 library, adding it.",
+                                        "Key {} not found in the PKCS 11 library, keeping it.",
                                         key_triple
                                     );
                                 } else {
-                                    warn!("Key found in the PKCS 11 library, adding it.");
+                                    warn!("Key not found in the PKCS 11 library, keeping it.");
                                 }
-                                let _ = local_ids_handle.insert(key_id);
+                                present_ids.push(key_id);
                             }
-                            Err(ResponseStatus::PsaErrorDoesNotExist) => {
+                            _ => {
                                 if crate::utils::GlobalConfig::log_error_details() {
                                     warn!(
                                         "Key {} not found in the PKCS 11 library, deleting it.",
@@ -145,30 +422,37 @@ impl Provider {
                                 } else {
                                     warn!("Key not found in the PKCS 11 library, deleting it.");
                                 }
-                                to_remove.push(key_triple.clone());
-                            }
-                            Err(e) => {
-                                format_error!("Error finding key objects", e);
-                                return None;
+                                to_remove.push(key_triple);
                             }
                         }
                     }
                 }
-                Err(string) => {
-                    format_error!("Key Info Manager error", string);
-                    return None;
-                }
-            };
-            for key_triple in to_remove.iter() {
-                if pkcs11_provider
-                    .key_info_store
-                    .remove_key_info(&key_triple)
-                    .is_err()
-                {
-                    return None;
-                }
             }
+            Err(string) => {
+                format_error!("Key Info Manager error", string);
+                return None;
+            }
+        };
+        for key_triple in to_remove.iter() {
+            if pkcs11_provider
+                .key_info_store
+                .remove_key_info(&key_triple)
+                .is_err()
+            {
+                return None;
+            }
+        }
+        pkcs11_provider
+            .local_ids
+            .write()
+            .expect("Local ID lock poisoned")
+            .extend(present_ids);
+
+        if let Err(e) = pkcs11_provider.find_unmapped_ids() {
+            format_error!("Failed to list the key objects of the token", e);
+            return None;
         }
+        let _ = pkcs11_provider.check_local_ids().ok()?;
 
         if pkcs11_provider.software_public_operations {
             psa_crypto::init().expect(
@@ -185,19 +469,75 @@ impl Provider {
     // * serial session
     // * logged in if the pin is set
     // * set on the slot in the provider
+    // * retried a few times if the token has too many sessions open
     fn new_session(&self) -> Result<Session> {
+        self.open_session(true)
+    }
+
+    // Same as `new_session` but read-only, for the operations which do not modify the token.
+    fn new_read_only_session(&self) -> Result<Session> {
+        self.open_session(false)
+    }
+
+    fn open_session(&self, read_write: bool) -> Result<Session> {
         let mut flags = Flags::new();
-        let _ = flags.set_rw_session(true).set_serial_session(true);
+        let _ = flags
+            .set_rw_session(session_read_write(read_write, self.read_only))
+            .set_serial_session(true);
 
-        let session = self
-            .backend
-            .open_session_no_callback(self.slot_number, flags)
-            .map_err(to_response_status)?;
+        let session = utils::retry_on_session_count(|| {
+            self.backend
+                .open_session_no_callback(self.slot_number, flags)
+        })
+        .map_err(to_response_status)?;
+        self.counters.increment(Counter::SessionOpen);
 
-        session.login(UserType::User).map_err(to_response_status)?;
+        if self.login {
+            session.login(UserType::User).map_err(to_response_status)?;
+        }
 
         Ok(session)
     }
+
+    /// Call `f`, which uses `session`, and retry it once after logging in again if it failed
+    /// because the session was logged out and `relogin` is configured.
+    fn with_relogin<T>(
+        &self,
+        session: &Session,
+        f: impl FnMut() -> cryptoki::Result<T>,
+    ) -> cryptoki::Result<T> {
+        utils::retry_on_user_not_logged_in(
+            self.relogin && self.login,
+            || session.login(UserType::User),
+            f,
+        )
+    }
+
+    /// Call `f` with `session` and retry it once with a new session, opened the same way, if the
+    /// token closed the session.
+    fn with_session_retry<T>(
+        &self,
+        session: &Session,
+        read_write: bool,
+        f: impl FnMut(&Session) -> cryptoki::Result<T>,
+    ) -> Result<T> {
+        utils::retry_on_session_closed(session, || self.open_session(read_write), f)
+    }
+
+    /// Refuse the operations using private keys or modifying the token when only the ones using
+    /// public keys are allowed.
+    fn check_private_operations_allowed(&self) -> Result<()> {
+        if self.public_only {
+            error!("No user pin set, only the operations using public keys are allowed.");
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+        Ok(())
+    }
+
+    /// Refuse the operations modifying the token when the provider is read-only.
+    fn check_token_writes_allowed(&self) -> Result<()> {
+        check_writes_allowed(self.read_only)
+    }
 }
 
 impl Provide for Provider {
@@ -227,7 +567,7 @@ impl Provide for Provider {
         _op: list_keys::Operation,
     ) -> Result<list_keys::Result> {
         Ok(list_keys::Result {
-            keys: self.key_info_store.list_keys(&app_name)?,
+            keys: self.list_keys_internal(app_name)?,
         })
     }
 
@@ -248,7 +588,7 @@ impl Provide for Provider {
         op: psa_generate_key::Operation,
     ) -> Result<psa_generate_key::Result> {
         trace!("psa_generate_key ingress");
-        self.psa_generate_key_internal(app_name, op)
+        self.psa_generate_key_internal(app_name, op, None, None)
     }
 
     fn psa_import_key(
@@ -257,7 +597,7 @@ impl Provide for Provider {
         op: psa_import_key::Operation,
     ) -> Result<psa_import_key::Result> {
         trace!("psa_import_key ingress");
-        self.psa_import_key_internal(app_name, op)
+        self.psa_import_key_internal(app_name, op, None)
     }
 
     fn psa_export_public_key(
@@ -275,7 +615,7 @@ impl Provide for Provider {
         op: psa_destroy_key::Operation,
     ) -> Result<psa_destroy_key::Result> {
         trace!("psa_destroy_key ingress");
-        self.psa_destroy_key_internal(app_name, op)
+        self.psa_destroy_key_internal(app_name, op, None)
     }
 
     fn psa_sign_hash(
@@ -338,7 +678,28 @@ pub struct ProviderBuilder {
     pkcs11_library_path: Option<String>,
     slot_number: Option<u64>,
     user_pin: Option<SecretString>,
+    user_pin_env: Option<String>,
+    user_pin_file: Option<String>,
     software_public_operations: Option<bool>,
+    rsa_public_exponent: Option<u32>,
+    check_imported_keys: Option<bool>,
+    disallowed_hashes: Option<Vec<Hash>>,
+    #[derivative(Debug = "ignore")]
+    key_id_allocator: Option<Box<dyn KeyIdAllocator>>,
+    startup_consistency_check: Option<String>,
+    startup_consistency_check_threads: Option<usize>,
+    key_id_discrepancy: Option<String>,
+    verify_cache_size: Option<usize>,
+    key_name_max_length: Option<usize>,
+    key_name_reserved_characters: Option<String>,
+    sign_replay_window: Option<usize>,
+    check_generated_key_ids: Option<bool>,
+    missing_user_pin: Option<String>,
+    relogin: Option<bool>,
+    check_key_types: Option<bool>,
+    deterministic_key_ids: Option<bool>,
+    key_id_width: Option<usize>,
+    read_only: Option<bool>,
 }
 
 impl ProviderBuilder {
@@ -349,7 +710,27 @@ impl ProviderBuilder {
             pkcs11_library_path: None,
             slot_number: None,
             user_pin: None,
+            user_pin_env: None,
+            user_pin_file: None,
             software_public_operations: None,
+            rsa_public_exponent: None,
+            check_imported_keys: None,
+            disallowed_hashes: None,
+            key_id_allocator: None,
+            startup_consistency_check: None,
+            startup_consistency_check_threads: None,
+            key_id_discrepancy: None,
+            verify_cache_size: None,
+            key_name_max_length: None,
+            key_name_reserved_characters: None,
+            sign_replay_window: None,
+            check_generated_key_ids: None,
+            missing_user_pin: None,
+            relogin: None,
+            check_key_types: None,
+            deterministic_key_ids: None,
+            key_id_width: None,
+            read_only: None,
         }
     }
 
@@ -386,6 +767,20 @@ impl ProviderBuilder {
         self
     }
 
+    /// Specify the environment variable to read the user pin from
+    pub fn with_user_pin_env(mut self, user_pin_env: Option<String>) -> ProviderBuilder {
+        self.user_pin_env = user_pin_env;
+
+        self
+    }
+
+    /// Specify the file to read the user pin from
+    pub fn with_user_pin_file(mut self, user_pin_file: Option<String>) -> ProviderBuilder {
+        self.user_pin_file = user_pin_file;
+
+        self
+    }
+
     /// Specify the `software_public_operations` flag
     pub fn with_software_public_operations(
         mut self,
@@ -396,6 +791,162 @@ impl ProviderBuilder {
         self
     }
 
+    /// Specify the public exponent of generated RSA keys
+    pub fn with_rsa_public_exponent(mut self, rsa_public_exponent: Option<u32>) -> ProviderBuilder {
+        self.rsa_public_exponent = rsa_public_exponent;
+
+        self
+    }
+
+    /// Specify the `check_imported_keys` flag
+    pub fn with_check_imported_keys(
+        mut self,
+        check_imported_keys: Option<bool>,
+    ) -> ProviderBuilder {
+        self.check_imported_keys = check_imported_keys;
+
+        self
+    }
+
+    /// Specify the hash algorithms not allowed for signing and verification
+    pub fn with_disallowed_hashes(
+        mut self,
+        disallowed_hashes: Option<Vec<Hash>>,
+    ) -> ProviderBuilder {
+        self.disallowed_hashes = disallowed_hashes;
+
+        self
+    }
+
+    /// Specify the allocator used to pick the IDs of new keys
+    pub fn with_key_id_allocator(
+        mut self,
+        key_id_allocator: Box<dyn KeyIdAllocator>,
+    ) -> ProviderBuilder {
+        self.key_id_allocator = Some(key_id_allocator);
+
+        self
+    }
+
+    /// Specify what to do at startup with stored keys missing from the token
+    pub fn with_startup_consistency_check(
+        mut self,
+        startup_consistency_check: Option<String>,
+    ) -> ProviderBuilder {
+        self.startup_consistency_check = startup_consistency_check;
+
+        self
+    }
+
+    /// Specify the number of threads looking up the stored keys on the token at startup
+    pub fn with_startup_consistency_check_threads(
+        mut self,
+        startup_consistency_check_threads: Option<usize>,
+    ) -> ProviderBuilder {
+        self.startup_consistency_check_threads = startup_consistency_check_threads;
+
+        self
+    }
+
+    /// Specify what to do when the local key IDs and the Key Info Manager disagree
+    pub fn with_key_id_discrepancy(
+        mut self,
+        key_id_discrepancy: Option<String>,
+    ) -> ProviderBuilder {
+        self.key_id_discrepancy = key_id_discrepancy;
+
+        self
+    }
+
+    /// Specify the number of successful verifications to cache
+    pub fn with_verify_cache_size(mut self, verify_cache_size: Option<usize>) -> ProviderBuilder {
+        self.verify_cache_size = verify_cache_size;
+
+        self
+    }
+
+    /// Specify the maximum length of the names of new keys
+    pub fn with_key_name_max_length(
+        mut self,
+        key_name_max_length: Option<usize>,
+    ) -> ProviderBuilder {
+        self.key_name_max_length = key_name_max_length;
+
+        self
+    }
+
+    /// Specify the characters not allowed in the names of new keys
+    pub fn with_key_name_reserved_characters(
+        mut self,
+        key_name_reserved_characters: Option<String>,
+    ) -> ProviderBuilder {
+        self.key_name_reserved_characters = key_name_reserved_characters;
+
+        self
+    }
+
+    /// Specify the number of hashes signed with each key which can not be signed again
+    pub fn with_sign_replay_window(mut self, sign_replay_window: Option<usize>) -> ProviderBuilder {
+        self.sign_replay_window = sign_replay_window;
+
+        self
+    }
+
+    /// Specify the `check_generated_key_ids` flag
+    pub fn with_check_generated_key_ids(
+        mut self,
+        check_generated_key_ids: Option<bool>,
+    ) -> ProviderBuilder {
+        self.check_generated_key_ids = check_generated_key_ids;
+
+        self
+    }
+
+    /// Specify what to do when no user pin is set
+    pub fn with_missing_user_pin(mut self, missing_user_pin: Option<String>) -> ProviderBuilder {
+        self.missing_user_pin = missing_user_pin;
+
+        self
+    }
+
+    /// Specify the `relogin` flag
+    pub fn with_relogin(mut self, relogin: Option<bool>) -> ProviderBuilder {
+        self.relogin = relogin;
+
+        self
+    }
+
+    /// Specify the `check_key_types` flag
+    pub fn with_check_key_types(mut self, check_key_types: Option<bool>) -> ProviderBuilder {
+        self.check_key_types = check_key_types;
+
+        self
+    }
+
+    /// Specify the `deterministic_key_ids` flag, used when no key ID allocator is given
+    pub fn with_deterministic_key_ids(
+        mut self,
+        deterministic_key_ids: Option<bool>,
+    ) -> ProviderBuilder {
+        self.deterministic_key_ids = deterministic_key_ids;
+
+        self
+    }
+
+    /// Specify the width in bytes of the IDs of new keys
+    pub fn with_key_id_width(mut self, key_id_width: Option<usize>) -> ProviderBuilder {
+        self.key_id_width = key_id_width;
+
+        self
+    }
+
+    /// Specify if the provider must never modify the token
+    pub fn with_read_only(mut self, read_only: Option<bool>) -> ProviderBuilder {
+        self.read_only = read_only;
+
+        self
+    }
+
     /// Attempt to build a PKCS11 provider
     pub fn build(self) -> std::io::Result<Provider> {
         let library_path = self
@@ -415,6 +966,55 @@ impl ProviderBuilder {
             ))
         })?;
 
+        let public_exponent = match self.rsa_public_exponent {
+            Some(exponent) => utils::public_exponent_bytes(exponent).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "RSA public exponent must be odd and at least 3",
+                )
+            })?,
+            None => utils::PUBLIC_EXPONENT.to_vec(),
+        };
+        let consistency_check = match self.startup_consistency_check {
+            Some(ref mode) => mode.parse()?,
+            None => ConsistencyCheck::Delete,
+        };
+        let mut key_name_rules = KeyNameRules::default();
+        if let Some(max_len) = self.key_name_max_length {
+            key_name_rules.max_len = max_len;
+        }
+        if let Some(ref reserved_chars) = self.key_name_reserved_characters {
+            key_name_rules.reserved_chars = reserved_chars.chars().collect();
+        }
+        let id_discrepancy = match self.key_id_discrepancy {
+            Some(ref mode) => mode.parse()?,
+            None => IdDiscrepancy::Log,
+        };
+        let key_id_allocator = match self.key_id_allocator {
+            Some(allocator) => allocator,
+            None if self.deterministic_key_ids.unwrap_or(false) => {
+                Box::new(DeterministicKeyIdAllocator)
+            }
+            None => Box::new(RandomKeyIdAllocator),
+        };
+        let key_id_width = self.key_id_width.unwrap_or(key_id::LEGACY_KEY_ID_WIDTH);
+        if !key_id::KEY_ID_WIDTHS.contains(&key_id_width) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "key ID width must be 4, 8 or 16 bytes",
+            ));
+        }
+        let missing_user_pin = match self.missing_user_pin {
+            Some(ref mode) => mode.parse()?,
+            None => MissingUserPin::NoLogin,
+        };
+        let user_pin = resolve_user_pin(
+            self.user_pin,
+            self.user_pin_env.as_deref(),
+            self.user_pin_file.as_deref(),
+        )?;
+        let public_only = check_user_pin(user_pin.is_some(), missing_user_pin)?;
+
         let backend = Pkcs11::new(library_path).map_err(|e| {
             format_error!("Error creating a PKCS 11 context", e);
             Error::new(ErrorKind::InvalidData, "error creating PKCS 11 context")
@@ -432,9 +1032,150 @@ impl ProviderBuilder {
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing key info store"))?,
             backend,
             slot,
-            self.user_pin,
+            user_pin,
             self.software_public_operations.unwrap_or(false),
+            public_exponent,
+            self.check_imported_keys.unwrap_or(false),
+            self.disallowed_hashes.unwrap_or_default(),
+            key_id_allocator,
+            consistency_check,
+            self.startup_consistency_check_threads.unwrap_or(1),
+            id_discrepancy,
+            self.verify_cache_size.unwrap_or(0),
+            key_name_rules,
+            self.sign_replay_window.unwrap_or(0),
+            self.check_generated_key_ids.unwrap_or(false),
+            public_only,
+            self.relogin.unwrap_or(false),
+            self.check_key_types.unwrap_or(false),
+            key_id_width,
+            self.read_only.unwrap_or(false),
         )
         .ok_or_else(|| Error::new(ErrorKind::InvalidData, "PKCS 11 initialization failed"))?)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_user_pin, check_writes_allowed, resolve_user_pin, session_read_write,
+        ConsistencyCheck, IdDiscrepancy, MissingUserPin,
+    };
+    use parsec_interface::requests::ResponseStatus;
+    use parsec_interface::secrecy::{ExposeSecret, SecretString};
+    use std::fs;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_consistency_check_modes() {
+        assert_eq!(
+            "off".parse::<ConsistencyCheck>().unwrap(),
+            ConsistencyCheck::Off
+        );
+        assert_eq!(
+            "warn".parse::<ConsistencyCheck>().unwrap(),
+            ConsistencyCheck::Warn
+        );
+        assert_eq!(
+            "delete".parse::<ConsistencyCheck>().unwrap(),
+            ConsistencyCheck::Delete
+        );
+        assert_eq!(
+            "fail".parse::<ConsistencyCheck>().unwrap(),
+            ConsistencyCheck::Fail
+        );
+    }
+
+    #[test]
+    fn parse_id_discrepancy_modes() {
+        assert_eq!("log".parse::<IdDiscrepancy>().unwrap(), IdDiscrepancy::Log);
+        assert_eq!(
+            "heal".parse::<IdDiscrepancy>().unwrap(),
+            IdDiscrepancy::Heal
+        );
+        assert_eq!(
+            "fail".parse::<IdDiscrepancy>().unwrap(),
+            IdDiscrepancy::Fail
+        );
+        assert!("ignore".parse::<IdDiscrepancy>().is_err());
+    }
+
+    #[test]
+    fn reject_unknown_consistency_check_mode() {
+        assert!("abort".parse::<ConsistencyCheck>().is_err());
+    }
+
+    #[test]
+    fn login_required_without_pin() {
+        // The token requires users to log in but no pin is set.
+        assert!(check_user_pin(false, MissingUserPin::Fail).is_err());
+        assert!(check_user_pin(false, MissingUserPin::PublicOnly).unwrap());
+        assert!(!check_user_pin(false, MissingUserPin::NoLogin).unwrap());
+
+        assert!(!check_user_pin(true, MissingUserPin::Fail).unwrap());
+        assert!(!check_user_pin(true, MissingUserPin::PublicOnly).unwrap());
+        assert_eq!(
+            "public-only".parse::<MissingUserPin>().unwrap(),
+            MissingUserPin::PublicOnly
+        );
+        assert!("none".parse::<MissingUserPin>().is_err());
+    }
+
+    #[test]
+    fn read_only_mode_refuses_writes() {
+        assert_eq!(
+            check_writes_allowed(true).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        assert!(check_writes_allowed(false).is_ok());
+
+        // Read operations still get a session, but never a read-write one.
+        assert!(!session_read_write(true, true));
+        assert!(!session_read_write(false, true));
+        assert!(session_read_write(true, false));
+        assert!(!session_read_write(false, false));
+    }
+
+    #[test]
+    fn user_pin_sources() {
+        let pin = |user_pin: Option<SecretString>| user_pin.unwrap().expose_secret().clone();
+
+        let configured = SecretString::from_str("123456").unwrap();
+        assert_eq!(
+            pin(resolve_user_pin(Some(configured), None, None).unwrap()),
+            "123456"
+        );
+        assert!(resolve_user_pin(None, None, None).unwrap().is_none());
+
+        std::env::set_var("PARSEC_TEST_USER_PIN", "234567");
+        let from_env = resolve_user_pin(None, Some("PARSEC_TEST_USER_PIN"), None).unwrap();
+        assert!(!format!("{:?}", from_env).contains("234567"));
+        assert_eq!(pin(from_env), "234567");
+
+        let path = env!("OUT_DIR").to_owned() + "/user_pin";
+        fs::write(&path, "345678\n").unwrap();
+        assert_eq!(
+            pin(resolve_user_pin(None, None, Some(&path)).unwrap()),
+            "345678"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_user_pin_source() {
+        let error = resolve_user_pin(None, Some("PARSEC_TEST_USER_PIN_UNSET"), None).unwrap_err();
+        assert!(error.to_string().contains("PARSEC_TEST_USER_PIN_UNSET"));
+
+        let path = env!("OUT_DIR").to_owned() + "/missing_user_pin";
+        let error = resolve_user_pin(None, None, Some(&path)).unwrap_err();
+        assert!(error.to_string().contains("missing_user_pin"));
+
+        // The pin sources are exclusive.
+        assert!(resolve_user_pin(
+            Some(SecretString::from_str("123456").unwrap()),
+            Some("PARSEC_TEST_USER_PIN_UNSET"),
+            None
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::KeyId;
+use log::error;
+use parsec_interface::requests::{ResponseStatus, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Remembers the last hashes signed with each key to refuse signing the same one again, for
+/// challenge-response protocols where a challenge must only be answered once.
+///
+/// At most `window` hashes are kept per key, the oldest being forgotten first. A window of 0
+/// disables the protection.
+#[derive(Debug)]
+pub(super) struct SignedHashes {
+    window: usize,
+    keys: Mutex<HashMap<KeyId, RecentHashes>>,
+}
+
+#[derive(Debug, Default)]
+struct RecentHashes {
+    hashes: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl SignedHashes {
+    pub(super) fn new(window: usize) -> SignedHashes {
+        SignedHashes {
+            window,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a hash about to be signed with a key. Fails with `PsaErrorAlreadyExists` if it was
+    /// recently signed with that key.
+    pub(super) fn record(&self, key_id: KeyId, hash: &[u8]) -> Result<()> {
+        if self.window == 0 {
+            return Ok(());
+        }
+        let mut keys = self.keys.lock().expect("Signed hashes lock poisoned");
+        let recent = keys.entry(key_id).or_default();
+        if !recent.hashes.insert(hash.to_vec()) {
+            error!("This hash was already signed with this key, refusing to sign it again.");
+            return Err(ResponseStatus::PsaErrorAlreadyExists);
+        }
+        recent.order.push_back(hash.to_vec());
+        if recent.order.len() > self.window {
+            if let Some(oldest) = recent.order.pop_front() {
+                let _ = recent.hashes.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget a hash recorded with `record`, if signing it failed.
+    pub(super) fn forget(&self, key_id: KeyId, hash: &[u8]) {
+        if self.window == 0 {
+            return;
+        }
+        let mut keys = self.keys.lock().expect("Signed hashes lock poisoned");
+        if let Some(recent) = keys.get_mut(&key_id) {
+            if recent.hashes.remove(hash) {
+                recent.order.retain(|recorded| recorded != hash);
+            }
+        }
+    }
+
+    /// Forget all the hashes signed with a key, when it is destroyed.
+    pub(super) fn forget_key(&self, key_id: KeyId) {
+        if self.window == 0 {
+            return;
+        }
+        let _ = self
+            .keys
+            .lock()
+            .expect("Signed hashes lock poisoned")
+            .remove(&key_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SignedHashes;
+    use crate::providers::pkcs11::KeyId;
+    use parsec_interface::requests::ResponseStatus;
+
+    #[test]
+    fn same_hash_signed_twice_is_refused() {
+        let signed_hashes = SignedHashes::new(8);
+        signed_hashes.record(KeyId::from(1), &[0xaa; 32]).unwrap();
+
+        assert_eq!(
+            signed_hashes
+                .record(KeyId::from(1), &[0xaa; 32])
+                .unwrap_err(),
+            ResponseStatus::PsaErrorAlreadyExists
+        );
+        // Other hashes and other keys are not affected.
+        signed_hashes.record(KeyId::from(1), &[0xbb; 32]).unwrap();
+        signed_hashes.record(KeyId::from(2), &[0xaa; 32]).unwrap();
+    }
+
+    #[test]
+    fn oldest_hash_is_evicted() {
+        let signed_hashes = SignedHashes::new(2);
+        for hash in 0..3 {
+            signed_hashes.record(KeyId::from(1), &[hash; 32]).unwrap();
+        }
+
+        signed_hashes.record(KeyId::from(1), &[0; 32]).unwrap();
+        assert!(signed_hashes.record(KeyId::from(1), &[2; 32]).is_err());
+    }
+
+    #[test]
+    fn forgotten_hash_can_be_signed() {
+        let signed_hashes = SignedHashes::new(2);
+        signed_hashes.record(KeyId::from(1), &[0; 32]).unwrap();
+        signed_hashes.forget(KeyId::from(1), &[0; 32]);
+        signed_hashes.record(KeyId::from(1), &[0; 32]).unwrap();
+
+        signed_hashes.forget_key(KeyId::from(1));
+        signed_hashes.record(KeyId::from(1), &[0; 32]).unwrap();
+    }
+
+    #[test]
+    fn disabled_protection_allows_repeats() {
+        let signed_hashes = SignedHashes::new(0);
+        signed_hashes.record(KeyId::from(1), &[0; 32]).unwrap();
+        signed_hashes.record(KeyId::from(1), &[0; 32]).unwrap();
+    }
+}
@@ -1,18 +1,35 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
+use super::KeyId;
 use cryptoki::types::function::RvError;
-use cryptoki::types::object::Attribute;
+use cryptoki::types::mechanism::rsa::PkcsPssParams;
+use cryptoki::types::mechanism::Mechanism;
+use cryptoki::types::object::{Attribute, AttributeType, KeyType, ObjectClass};
+use cryptoki::types::Ulong;
 use cryptoki::Error;
-use log::error;
+use log::{error, warn};
 use parsec_interface::operations::psa_algorithm::*;
 use parsec_interface::operations::psa_key_attributes::*;
 use parsec_interface::requests::ResponseStatus;
 use parsec_interface::requests::Result;
-use picky_asn1_x509::{AlgorithmIdentifier, DigestInfo, SHAVariant};
+use picky_asn1::wrapper::IntegerAsn1;
+use picky_asn1_x509::{AlgorithmIdentifier, DigestInfo, RSAPublicKey, SHAVariant};
+use std::convert::TryFrom;
+use std::ffi::c_void;
+use std::thread;
+use std::time::Duration;
 
-// Public exponent value for all RSA keys.
+// Default public exponent value for RSA keys.
 pub const PUBLIC_EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
 
+// Maximum length in bytes of the labels given to key objects.
+pub const MAX_LABEL_LEN: usize = 64;
+
+// Number of attempts to open a session when the token has too many sessions open.
+const SESSION_COUNT_ATTEMPTS: u32 = 3;
+// Delay before the first retry, doubled after each attempt.
+const SESSION_COUNT_BACKOFF: Duration = Duration::from_millis(10);
+
 /// Convert the PKCS 11 library specific error values to ResponseStatus values that are returned on
 /// the wire protocol
 ///
@@ -22,6 +39,10 @@ pub const PUBLIC_EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
 /// that it failed in an unexpected way and hence the PsaErrorCommunicationFailure error.
 /// The errors translated to response status are related with signature verification failure, lack
 /// of memory, hardware failure, corruption detection, lack of entropy and unsupported operations.
+/// Errors of the token itself (`CKR_DEVICE_ERROR`, `CKR_GENERAL_ERROR`, failed self tests) are
+/// reported as PsaErrorHardwareFailure, so that they are not mistaken for transport issues.
+/// A full token (`CKR_DEVICE_MEMORY`) is reported as PsaErrorInsufficientStorage, distinct from the
+/// host running out of memory (`CKR_HOST_MEMORY`, PsaErrorInsufficientMemory).
 pub fn to_response_status(error: Error) -> ResponseStatus {
     match error {
         Error::LibraryLoading(e) => {
@@ -52,9 +73,26 @@ pub fn rv_to_response_status(rv: RvError) -> ResponseStatus {
         RvError::TokenNotRecognized => ResponseStatus::PsaErrorHardwareFailure,
         RvError::RandomNoRng => ResponseStatus::PsaErrorInsufficientEntropy,
         RvError::StateUnsaveable => ResponseStatus::PsaErrorHardwareFailure,
+        e @ RvError::GeneralError | e @ RvError::FipsSelfTestFailed => {
+            format_error!("Token failure", e);
+            ResponseStatus::PsaErrorHardwareFailure
+        }
+        RvError::KeyFunctionNotPermitted => ResponseStatus::PsaErrorNotPermitted,
+        RvError::KeyUnextractable => ResponseStatus::PsaErrorNotPermitted,
+        RvError::BufferTooSmall => ResponseStatus::PsaErrorBufferTooSmall,
+        RvError::SessionCount => {
+            error!("The token has too many sessions open.");
+            ResponseStatus::PsaErrorInsufficientMemory
+        }
+        RvError::SessionReadWriteSoExists => {
+            error!("A Security Officer session is open on the token, refusing user sessions.");
+            ResponseStatus::PsaErrorBadState
+        }
         s @ RvError::CurveNotSupported
         | s @ RvError::DomainParamsInvalid
-        | s @ RvError::FunctionNotSupported => {
+        | s @ RvError::FunctionNotSupported
+        | s @ RvError::MechanismInvalid
+        | s @ RvError::MechanismParamInvalid => {
             if crate::utils::GlobalConfig::log_error_details() {
                 error!("Not supported value ({:?})", s);
             }
@@ -67,32 +105,335 @@ pub fn rv_to_response_status(rv: RvError) -> ResponseStatus {
     }
 }
 
+/// Check that a label given by a client is not empty, not longer than `MAX_LABEL_LEN` bytes and
+/// does not contain control characters, and convert it to the `CKA_LABEL` value.
+pub fn sanitize_label(label: &str) -> Result<Vec<u8>> {
+    if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        error!(
+            "Key labels must be between 1 and {} bytes long.",
+            MAX_LABEL_LEN
+        );
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    if label.chars().any(char::is_control) {
+        error!("Key labels must not contain control characters.");
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    Ok(label.as_bytes().to_vec())
+}
+
+/// Rules the names of new keys must follow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyNameRules {
+    /// Maximum length of a key name in bytes
+    pub max_len: usize,
+    /// Characters not allowed in key names
+    pub reserved_chars: Vec<char>,
+}
+
+impl Default for KeyNameRules {
+    fn default() -> Self {
+        KeyNameRules {
+            max_len: MAX_LABEL_LEN,
+            reserved_chars: Vec::new(),
+        }
+    }
+}
+
+impl KeyNameRules {
+    /// Check that a key name is not empty, not too long and does not contain control or reserved
+    /// characters.
+    pub fn check(&self, key_name: &str) -> Result<()> {
+        if key_name.is_empty() {
+            error!("Key names must not be empty.");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if key_name.len() > self.max_len {
+            error!("Key names must not be longer than {} bytes.", self.max_len);
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if key_name.chars().any(char::is_control) {
+            error!("Key names must not contain control characters.");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if let Some(c) = key_name.chars().find(|c| self.reserved_chars.contains(c)) {
+            error!("Key names must not contain the reserved character '{}'.", c);
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        Ok(())
+    }
+}
+
+/// Maximum size of a message encrypted with an RSA key of `key_bits` bits: the modulus size minus
+/// the overhead of the padding scheme.
+pub fn max_plaintext_len(alg: AsymmetricEncryption, key_bits: usize) -> usize {
+    let padding_len = match alg {
+        AsymmetricEncryption::RsaPkcs1v15Crypt => 11,
+        AsymmetricEncryption::RsaOaep { hash_alg } => 2 * hash_alg.hash_length() + 2,
+    };
+    ((key_bits + 7) / 8).saturating_sub(padding_len)
+}
+
+/// Check that a message is not too long to be encrypted with an RSA key of `key_bits` bits.
+pub fn check_plaintext_len(alg: AsymmetricEncryption, key_bits: usize, len: usize) -> Result<()> {
+    let max_len = max_plaintext_len(alg, key_bits);
+    if len > max_len {
+        error!(
+            "Plaintext of {} bytes is longer than the {} bytes allowed by the key and algorithm.",
+            len, max_len
+        );
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    Ok(())
+}
+
+/// Check that a ciphertext is not longer than the modulus of an RSA key of `key_bits` bits.
+pub fn check_ciphertext_len(key_bits: usize, len: usize) -> Result<()> {
+    let max_len = (key_bits + 7) / 8;
+    if len > max_len {
+        error!(
+            "Ciphertext of {} bytes is longer than the {} bytes modulus of the key.",
+            len, max_len
+        );
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    Ok(())
+}
+
+/// Check that the hash of a signature algorithm is not one of the `disallowed` ones.
+pub fn is_hash_allowed(alg: AsymmetricSignature, disallowed: &[Hash]) -> bool {
+    match alg.hash() {
+        Some(SignHash::Specific(hash)) => !disallowed.contains(&hash),
+        _ => true,
+    }
+}
+
+/// Encode an RSA public exponent as the big-endian bytes of `CKA_PUBLIC_EXPONENT`.
+/// Returns `None` if the exponent is not odd or smaller than 3.
+pub fn public_exponent_bytes(exponent: u32) -> Option<Vec<u8>> {
+    if exponent < 3 || exponent % 2 == 0 {
+        return None;
+    }
+    Some(
+        exponent
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect(),
+    )
+}
+
+/// Call `open` until it does not fail with `CKR_SESSION_COUNT`, backing off between the attempts
+/// to give the other operations a chance to close their sessions.
+///
+/// The provider has no session pool in which to close an idle session: sessions are opened per
+/// operation and closed when it finishes, so waiting for other operations is the only way to get
+/// one back.
+pub fn retry_on_session_count<T>(
+    mut open: impl FnMut() -> cryptoki::Result<T>,
+) -> cryptoki::Result<T> {
+    let mut backoff = SESSION_COUNT_BACKOFF;
+    for _ in 1..SESSION_COUNT_ATTEMPTS {
+        match open() {
+            Err(Error::Pkcs11(RvError::SessionCount)) => {
+                warn!(
+                    "Too many sessions open on the token, retrying in {} ms.",
+                    backoff.as_millis()
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    open()
+}
+
+/// Call `f` and, if `relogin` is set and it fails with `CKR_USER_NOT_LOGGED_IN`, log in again with
+/// `login` and call it a second time.
+pub fn retry_on_user_not_logged_in<T>(
+    relogin: bool,
+    login: impl FnOnce() -> cryptoki::Result<()>,
+    mut f: impl FnMut() -> cryptoki::Result<T>,
+) -> cryptoki::Result<T> {
+    match f() {
+        Err(Error::Pkcs11(RvError::UserNotLoggedIn)) if relogin => {
+            warn!("The session was logged out, logging in again.");
+            login()?;
+            f()
+        }
+        result => result,
+    }
+}
+
+/// Call `f` with `session` and, if it fails because the token closed the session
+/// (`CKR_SESSION_CLOSED` or `CKR_SESSION_HANDLE_INVALID`), call it a second time with a new
+/// session from `reopen`. The operation is retried only once.
+pub fn retry_on_session_closed<S, T>(
+    session: &S,
+    reopen: impl FnOnce() -> Result<S>,
+    mut f: impl FnMut(&S) -> cryptoki::Result<T>,
+) -> Result<T> {
+    match f(session) {
+        Err(Error::Pkcs11(RvError::SessionClosed))
+        | Err(Error::Pkcs11(RvError::SessionHandleInvalid)) => {
+            warn!("The session was closed by the token, retrying with a new session.");
+            let session = reopen()?;
+            f(&session).map_err(to_response_status)
+        }
+        result => result.map_err(to_response_status),
+    }
+}
+
+/// Apply `f` to all the `items`, spreading them over `parallelism` threads. Each thread opens its
+/// own session with `open` and goes through its share of the items with it. The results are in the
+/// same order as `items`.
+pub fn parallel_with_sessions<T: Sync, S, R: Send>(
+    items: &[T],
+    parallelism: usize,
+    open: impl Fn() -> Result<S> + Sync,
+    f: impl Fn(&S, &T) -> Result<R> + Sync,
+) -> Result<Vec<R>> {
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+    let chunk_size = (items.len() + parallelism.max(1) - 1) / parallelism.max(1);
+    let (open, f) = (&open, &f);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let session = open()?;
+                    chunk
+                        .iter()
+                        .map(|item| f(&session, item))
+                        .collect::<Result<Vec<R>>>()
+                })
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(items.len());
+        for worker in workers {
+            results.extend(worker.join().expect("PKCS 11 worker thread panicked")?);
+        }
+        Ok(results)
+    })
+}
+
 // For PKCS 11, a key pair consists of two independant public and private keys. Both will share the
 // same key ID.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum KeyPairType {
     PublicKey,
     PrivateKey,
     Any,
 }
 
-pub fn key_pair_usage_flags_to_pkcs11_attributes(
-    usage_flags: UsageFlags,
+/// Check that an object found for the requested key pair type is of the matching class. Classes
+/// other than public keys can not be read so `None` is accepted for private keys.
+pub fn check_object_class(requested: &KeyPairType, class: Option<ObjectClass>) -> Result<()> {
+    let matches = match requested {
+        KeyPairType::PublicKey => class == Some(ObjectClass::PUBLIC_KEY),
+        KeyPairType::PrivateKey => class != Some(ObjectClass::PUBLIC_KEY),
+        KeyPairType::Any => true,
+    };
+    if !matches {
+        error!("The PKCS 11 library returned an object of the wrong class for the key.");
+        return Err(ResponseStatus::PsaErrorStorageFailure);
+    }
+    Ok(())
+}
+
+/// Pick the object of a key among the objects found on the token with its ID and class.
+///
+/// The public and private objects of a key are unique: several objects of the same class sharing
+/// the ID are reported as PsaErrorStorageFailure instead of using an arbitrary one. The objects of
+/// a key pair are found together when looking for any object of the key, the first is used.
+pub fn single_key_object<H: Copy>(key_type: &KeyPairType, objects: &[H]) -> Result<H> {
+    match (key_type, objects) {
+        (_, []) => Err(ResponseStatus::PsaErrorDoesNotExist),
+        (KeyPairType::Any, [object, ..]) | (_, [object]) => Ok(*object),
+        (_, objects) => {
+            error!(
+                "{} {:?} objects share the ID of the key on the token.",
+                objects.len(),
+                key_type
+            );
+            Err(ResponseStatus::PsaErrorStorageFailure)
+        }
+    }
+}
+
+/// Number of PKCS 11 objects backing a key of the given type: two for key pairs (a public and a
+/// private object sharing the same ID), one for everything else.
+pub fn key_objects_count(key_type: Type) -> usize {
+    match key_type {
+        Type::RsaKeyPair | Type::EccKeyPair { .. } | Type::DhKeyPair { .. } => 2,
+        _ => 1,
+    }
+}
+
+/// Convert the usage flags of the public and private halves of a key pair to PKCS 11 attributes of
+/// their templates.
+pub fn key_pair_policies_to_pkcs11_attributes(
+    public_usage_flags: UsageFlags,
+    private_usage_flags: UsageFlags,
     pub_template: &mut Vec<Attribute>,
     priv_template: &mut Vec<Attribute>,
 ) {
     priv_template.push(Attribute::Sign(
-        (usage_flags.sign_hash || usage_flags.sign_message).into(),
+        (private_usage_flags.sign_hash || private_usage_flags.sign_message).into(),
     ));
     pub_template.push(Attribute::Verify(
-        (usage_flags.verify_hash || usage_flags.verify_message).into(),
+        (public_usage_flags.verify_hash || public_usage_flags.verify_message).into(),
     ));
-    pub_template.push(Attribute::Encrypt((usage_flags.encrypt).into()));
-    priv_template.push(Attribute::Decrypt((usage_flags.decrypt).into()));
-    priv_template.push(Attribute::Derive((usage_flags.derive).into()));
-    priv_template.push(Attribute::Extractable((usage_flags.export).into()));
-    priv_template.push(Attribute::Sensitive((usage_flags.export).into()));
-    priv_template.push(Attribute::Copyable((usage_flags.copy).into()));
-    pub_template.push(Attribute::Copyable((usage_flags.copy).into()));
+    pub_template.push(Attribute::Encrypt((public_usage_flags.encrypt).into()));
+    priv_template.push(Attribute::Decrypt((private_usage_flags.decrypt).into()));
+    priv_template.push(Attribute::Derive((private_usage_flags.derive).into()));
+    priv_template.push(Attribute::Extractable((private_usage_flags.export).into()));
+    priv_template.push(Attribute::Sensitive((private_usage_flags.export).into()));
+    priv_template.push(Attribute::Copyable((private_usage_flags.copy).into()));
+    pub_template.push(Attribute::Copyable((public_usage_flags.copy).into()));
+}
+
+/// Check that the usage flags of the two halves of a key pair are consistent: each key only has
+/// the usages it can perform and the public key allows the counterpart of the private key usages.
+pub fn check_key_pair_policies(public: UsageFlags, private: UsageFlags) -> Result<()> {
+    if public.sign_hash || public.sign_message || public.decrypt || public.derive {
+        error!("A public key can not be used to sign, decrypt or derive.");
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    if private.verify_hash || private.verify_message || private.encrypt {
+        error!("A private key can not be used to verify or encrypt.");
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    if (private.sign_hash && !public.verify_hash)
+        || (private.sign_message && !public.verify_message)
+        || (private.decrypt && !public.encrypt)
+    {
+        error!("The public key must allow the counterpart of the private key usages.");
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    Ok(())
+}
+
+/// Usage flags allowing everything either of the halves of a key pair allows.
+pub fn usage_flags_union(public: UsageFlags, private: UsageFlags) -> UsageFlags {
+    UsageFlags {
+        export: public.export || private.export,
+        copy: public.copy || private.copy,
+        cache: public.cache || private.cache,
+        encrypt: public.encrypt,
+        decrypt: private.decrypt,
+        sign_message: private.sign_message,
+        verify_message: public.verify_message,
+        sign_hash: private.sign_hash,
+        verify_hash: public.verify_hash,
+        derive: private.derive,
+    }
 }
 
 /// Format the input data into ASN1 DigestInfo bytes
@@ -119,3 +460,912 @@ pub fn digest_info(alg: AsymmetricSignature, hash: Vec<u8>) -> Result<Vec<u8>> {
     // should not fail - if it does, there's some error in our stack
     .map_err(|_| ResponseStatus::PsaErrorGenericError)
 }
+
+/// Attributes read from the public object of an RSA key to export it.
+pub const RSA_PUBLIC_KEY_ATTRIBUTES: [AttributeType; 2] =
+    [AttributeType::Modulus, AttributeType::PublicExponent];
+
+/// Check that only public key material is read to export a key. Exporting the public part of a
+/// key pair must not depend on the policy of its private part, so it must never read from it.
+pub fn check_public_attributes(attribute_types: &[AttributeType]) -> Result<()> {
+    for attribute_type in attribute_types {
+        match attribute_type {
+            AttributeType::Modulus | AttributeType::ModulusBits | AttributeType::PublicExponent => {
+            }
+            _ => {
+                error!(
+                    "The {:?} attribute is not part of a public key.",
+                    attribute_type
+                );
+                return Err(ResponseStatus::PsaErrorNotPermitted);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Extract the modulus and the public exponent from the attributes read from an RSA public key.
+///
+/// The attributes the token reports as unavailable are left out by the PKCS 11 crate: a missing
+/// attribute is reported as `PsaErrorCommunicationFailure`. A zero-length attribute means that the
+/// object on the token is corrupt and is reported as `PsaErrorStorageFailure`.
+pub fn rsa_public_key_parts(attributes: Vec<Attribute>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut modulus = None;
+    let mut public_exponent = None;
+    for attribute in attributes {
+        match attribute {
+            Attribute::Modulus(value) => modulus = Some(value),
+            Attribute::PublicExponent(value) => public_exponent = Some(value),
+            _ => (),
+        }
+    }
+    let modulus = required_attribute(modulus, AttributeType::Modulus)?;
+    let public_exponent = required_attribute(public_exponent, AttributeType::PublicExponent)?;
+
+    Ok((modulus, public_exponent))
+}
+
+fn required_attribute(value: Option<Vec<u8>>, attribute_type: AttributeType) -> Result<Vec<u8>> {
+    match value {
+        Some(value) if !value.is_empty() => Ok(value),
+        Some(_) => {
+            error!(
+                "The {:?} attribute of the public key is empty, the object is corrupt.",
+                attribute_type
+            );
+            Err(ResponseStatus::PsaErrorStorageFailure)
+        }
+        None => {
+            error!(
+                "The {:?} attribute of the public key is unavailable.",
+                attribute_type
+            );
+            Err(ResponseStatus::PsaErrorCommunicationFailure)
+        }
+    }
+}
+
+/// Check that the attributes read from a generated object carry the ID of the key.
+///
+/// Some tokens ignore the `CKA_ID` of the generation templates. The PKCS 11 crate can not set it
+/// afterwards so such objects are reported as `PsaErrorStorageFailure`.
+pub fn check_key_id(key_id: KeyId, attributes: Vec<Attribute>) -> Result<()> {
+    let id = attributes
+        .into_iter()
+        .find_map(|attribute| match attribute {
+            Attribute::Id(id) => Some(id),
+            _ => None,
+        });
+    if id.as_deref() == Some(key_id.as_bytes()) {
+        Ok(())
+    } else {
+        error!("The token did not set the ID of the generated key.");
+        Err(ResponseStatus::PsaErrorStorageFailure)
+    }
+}
+
+/// Check that the type of a key object on the token matches the stored key type. `None` stands
+/// for a token key type that cannot be read with this version of the PKCS 11 crate, which is not
+/// RSA.
+pub fn check_key_type(stored_type: Type, token_type: Option<KeyType>) -> Result<()> {
+    let stored_rsa = matches!(stored_type, Type::RsaKeyPair | Type::RsaPublicKey);
+    if stored_rsa == (token_type == Some(KeyType::RSA)) {
+        Ok(())
+    } else {
+        if crate::utils::GlobalConfig::log_error_details() {
+            error!(
+                "The stored key type {:?} does not match the key type {:?} on the token.",
+                stored_type, token_type
+            );
+        } else {
+            error!("The stored key type does not match the key type on the token.");
+        }
+        Err(ResponseStatus::PsaErrorStorageFailure)
+    }
+}
+
+/// Check that the modulus of an RSA key object on the token has the stored key size. A stored size
+/// of 0 is not checked.
+pub fn check_key_size(stored_bits: usize, token_modulus: Option<&[u8]>) -> Result<()> {
+    if stored_bits == 0 {
+        return Ok(());
+    }
+    let token_bits = match token_modulus {
+        Some(modulus) => integer_bits(modulus),
+        None => {
+            error!("The modulus of the key object could not be read from the token.");
+            return Err(ResponseStatus::PsaErrorStorageFailure);
+        }
+    };
+    if token_bits != stored_bits {
+        error!(
+            "The key is {} bits on the token but {} bits in the Key Info Manager.",
+            token_bits, stored_bits
+        );
+        return Err(ResponseStatus::PsaErrorStorageFailure);
+    }
+    Ok(())
+}
+
+/// DER-encode an RSA public key from the unsigned big-endian bytes of its modulus and public
+/// exponent.
+pub fn rsa_public_key_der(modulus: Vec<u8>, public_exponent: Vec<u8>) -> Result<Vec<u8>> {
+    let key = RSAPublicKey {
+        modulus: IntegerAsn1::from_bytes_be_unsigned(modulus),
+        public_exponent: IntegerAsn1::from_bytes_be_unsigned(public_exponent),
+    };
+    picky_asn1_der::to_vec(&key).map_err(|err| {
+        format_error!("Could not serialise key elements", err);
+        ResponseStatus::PsaErrorGenericError
+    })
+}
+
+/// Size in bits of a big-endian unsigned integer.
+pub fn integer_bits(bytes: &[u8]) -> usize {
+    match bytes.iter().position(|byte| *byte != 0) {
+        Some(first) => (bytes.len() - first) * 8 - bytes[first].leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+/// Replace the stored size of a key with the size of its object on the token, if they differ.
+/// A stored size of 0, left unspecified when the key was imported, is filled in silently.
+pub fn reconcile_key_size(mut attributes: Attributes, token_bits: usize) -> Attributes {
+    if attributes.bits != 0 && attributes.bits != token_bits {
+        warn!(
+            "The key is {} bits on the token but {} bits in the Key Info Manager, using the token size.",
+            token_bits, attributes.bits
+        );
+    }
+    attributes.bits = token_bits;
+    attributes
+}
+
+/// Format the input data as expected by the PKCS 11 signature mechanism of the algorithm: ASN1
+/// DigestInfo bytes for PKCS#1 v1.5 and the hash itself for RSA-PSS.
+pub fn signature_input(alg: AsymmetricSignature, hash: Vec<u8>) -> Result<Vec<u8>> {
+    match alg {
+        AsymmetricSignature::RsaPss { .. } => Ok(hash),
+        _ => digest_info(alg, hash),
+    }
+}
+
+/// Get the `CK_RSA_PKCS_PSS_PARAMS` used for an RSA-PSS algorithm, or `None` for other algorithms.
+///
+/// The parameters are fully specified: both the message hash and the MGF1 hash are the hash of the
+/// algorithm and the salt length is the length of that hash. PKCS 11 has no trailer field
+/// parameter, the trailer is always 0xBC (`trailerFieldBC` in RFC 8017).
+///
+/// The PSA RSA-PSS algorithm has no other salt length. A token rejecting these parameters
+/// (`CKR_MECHANISM_PARAM_INVALID`) makes the operation fail with `PsaErrorNotSupported`.
+pub fn rsa_pss_params(alg: AsymmetricSignature) -> Result<Option<PkcsPssParams>> {
+    match Mechanism::try_from(Algorithm::from(alg)).map_err(to_response_status)? {
+        Mechanism::RsaPkcsPss(params) => Ok(Some(params)),
+        _ => Ok(None),
+    }
+}
+
+/// Get the mechanism used for an asymmetric encryption algorithm.
+///
+/// For RSA-OAEP, a non-empty `label` (the salt of the PSA operation) is set as the encoding
+/// parameter source data. The mechanism then points to `label`, which must outlive it.
+pub fn encryption_mechanism(alg: AsymmetricEncryption, label: Option<&[u8]>) -> Result<Mechanism> {
+    let mechanism = Mechanism::try_from(Algorithm::from(alg)).map_err(to_response_status)?;
+    match (mechanism, label) {
+        (Mechanism::RsaPkcsOaep(mut params), Some(label)) if !label.is_empty() => {
+            params.source_data = label.as_ptr() as *const c_void;
+            params.source_data_len = Ulong::from(label.len() as u64);
+            Ok(Mechanism::RsaPkcsOaep(params))
+        }
+        (mechanism, _) => Ok(mechanism),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_ciphertext_len, check_key_id, check_key_pair_policies, check_key_size,
+        check_key_type, check_object_class, check_plaintext_len, check_public_attributes,
+        encryption_mechanism, integer_bits, is_hash_allowed, key_objects_count,
+        key_pair_policies_to_pkcs11_attributes, parallel_with_sessions, public_exponent_bytes,
+        reconcile_key_size, retry_on_session_closed, retry_on_session_count,
+        retry_on_user_not_logged_in, rsa_pss_params, rsa_public_key_der, rsa_public_key_parts,
+        rv_to_response_status, sanitize_label, signature_input, single_key_object,
+        to_response_status, usage_flags_union, KeyId, KeyNameRules, KeyPairType, PUBLIC_EXPONENT,
+        RSA_PUBLIC_KEY_ATTRIBUTES, SESSION_COUNT_ATTEMPTS,
+    };
+    use crate::key_info_managers::test_utils::test_key_attributes;
+    use cryptoki::types::function::RvError;
+    use cryptoki::types::mechanism::rsa::PkcsMgfType;
+    use cryptoki::types::mechanism::{Mechanism, MechanismType};
+    use cryptoki::types::object::{Attribute, AttributeType, KeyType, ObjectClass};
+    use cryptoki::types::Ulong;
+    use cryptoki::Error;
+    use parsec_interface::operations::psa_algorithm::{
+        AsymmetricEncryption, AsymmetricSignature, Hash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, EccFamily, Type, UsageFlags,
+    };
+    use parsec_interface::requests::ResponseStatus;
+    use picky_asn1_x509::RSAPublicKey;
+    use std::collections::HashSet;
+    use std::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn key_pairs_have_two_objects() {
+        assert_eq!(key_objects_count(Type::RsaKeyPair), 2);
+        assert_eq!(
+            key_objects_count(Type::EccKeyPair {
+                curve_family: EccFamily::SecpR1
+            }),
+            2
+        );
+    }
+
+    #[test]
+    fn single_keys_have_one_object() {
+        assert_eq!(key_objects_count(Type::Aes), 1);
+        assert_eq!(key_objects_count(Type::Hmac), 1);
+        assert_eq!(key_objects_count(Type::RsaPublicKey), 1);
+    }
+
+    #[test]
+    fn duplicate_key_objects_are_storage_failure() {
+        assert_eq!(
+            single_key_object(&KeyPairType::PrivateKey, &[7]).unwrap(),
+            7
+        );
+        assert_eq!(
+            single_key_object::<u32>(&KeyPairType::PublicKey, &[]).unwrap_err(),
+            ResponseStatus::PsaErrorDoesNotExist
+        );
+
+        // Two private or two public objects with the ID of the key.
+        for key_type in &[KeyPairType::PrivateKey, KeyPairType::PublicKey] {
+            assert_eq!(
+                single_key_object(key_type, &[7, 8]).unwrap_err(),
+                ResponseStatus::PsaErrorStorageFailure
+            );
+        }
+        // The public and private objects of a key pair.
+        assert_eq!(single_key_object(&KeyPairType::Any, &[7, 8]).unwrap(), 7);
+    }
+
+    #[test]
+    fn rsa_pss_params_are_fully_specified() {
+        let params = rsa_pss_params(AsymmetricSignature::RsaPss {
+            hash_alg: Hash::Sha256.into(),
+        })
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(params.hash_alg, MechanismType::SHA256);
+        assert_eq!(params.mgf, PkcsMgfType::MGF1_SHA256);
+        assert_eq!(params.s_len, Ulong::from(32));
+    }
+
+    #[test]
+    fn rejected_rsa_pss_params_are_not_supported() {
+        assert_eq!(
+            to_response_status(Error::Pkcs11(RvError::MechanismParamInvalid)),
+            ResponseStatus::PsaErrorNotSupported
+        );
+        assert_eq!(
+            rv_to_response_status(RvError::MechanismInvalid),
+            ResponseStatus::PsaErrorNotSupported
+        );
+    }
+
+    #[test]
+    fn no_rsa_pss_params_for_pkcs1v15() {
+        assert!(rsa_pss_params(AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: Hash::Sha256.into(),
+        })
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    fn rsa_pss_signs_the_hash_itself() {
+        let hash = vec![0x5a; 32];
+        let alg = AsymmetricSignature::RsaPss {
+            hash_alg: Hash::Sha256.into(),
+        };
+
+        assert_eq!(signature_input(alg, hash.clone()).unwrap(), hash);
+    }
+
+    #[test]
+    fn der_and_raw_integer_encodings() {
+        // Modulus with the high bit set, as exported by `export_public_key_integers`.
+        let modulus = vec![0x80, 0x01];
+        let public_exponent = vec![0x01, 0x00, 0x01];
+
+        let der = rsa_public_key_der(modulus.clone(), public_exponent.clone()).unwrap();
+        // The DER modulus is a positive INTEGER: a zero byte is added before the high bit.
+        assert_eq!(
+            der,
+            vec![0x30, 0x0a, 0x02, 0x03, 0x00, 0x80, 0x01, 0x02, 0x03, 0x01, 0x00, 0x01]
+        );
+
+        let key: RSAPublicKey = picky_asn1_der::from_bytes(&der).unwrap();
+        assert_eq!(key.modulus.as_unsigned_bytes_be(), modulus.as_slice());
+        assert_eq!(
+            key.public_exponent.as_unsigned_bytes_be(),
+            public_exponent.as_slice()
+        );
+    }
+
+    #[test]
+    fn session_errors_are_distinct() {
+        assert_eq!(
+            rv_to_response_status(RvError::SessionCount),
+            ResponseStatus::PsaErrorInsufficientMemory
+        );
+        assert_eq!(
+            rv_to_response_status(RvError::SessionReadWriteSoExists),
+            ResponseStatus::PsaErrorBadState
+        );
+    }
+
+    #[test]
+    fn hardware_and_communication_errors_are_distinct() {
+        for rv in vec![
+            RvError::DeviceError,
+            RvError::DeviceRemoved,
+            RvError::GeneralError,
+            RvError::FipsSelfTestFailed,
+            RvError::TokenNotPresent,
+        ] {
+            assert_eq!(
+                rv_to_response_status(rv),
+                ResponseStatus::PsaErrorHardwareFailure
+            );
+        }
+        for rv in vec![
+            RvError::FunctionFailed,
+            RvError::SessionHandleInvalid,
+            RvError::CryptokiNotInitialized,
+        ] {
+            assert_eq!(
+                rv_to_response_status(rv),
+                ResponseStatus::PsaErrorCommunicationFailure
+            );
+        }
+        assert_eq!(
+            to_response_status(Error::Pkcs11(RvError::GeneralError)),
+            ResponseStatus::PsaErrorHardwareFailure
+        );
+        assert_eq!(
+            rv_to_response_status(RvError::KeyUnextractable),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
+
+    #[test]
+    fn full_token_is_insufficient_storage() {
+        assert_eq!(
+            to_response_status(Error::Pkcs11(RvError::DeviceMemory)),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        assert_eq!(
+            rv_to_response_status(RvError::DeviceMemory),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        assert_eq!(
+            rv_to_response_status(RvError::HostMemory),
+            ResponseStatus::PsaErrorInsufficientMemory
+        );
+    }
+
+    #[test]
+    fn token_key_size_is_trusted() {
+        let attributes = test_key_attributes();
+
+        assert_eq!(reconcile_key_size(attributes, 2048), attributes);
+        let reconciled = reconcile_key_size(attributes, 1024);
+        assert_eq!(reconciled.bits, 1024);
+        assert_eq!(reconciled.policy, attributes.policy);
+
+        let unspecified = Attributes {
+            bits: 0,
+            ..attributes
+        };
+        assert_eq!(reconcile_key_size(unspecified, 2048), attributes);
+    }
+
+    #[test]
+    fn retry_once_after_session_closed() {
+        let mut sessions = Vec::new();
+        let result = retry_on_session_closed(
+            &1,
+            || Ok(2),
+            |session: &u32| {
+                sessions.push(*session);
+                if *session == 1 {
+                    Err(Error::Pkcs11(RvError::SessionClosed))
+                } else {
+                    Ok(*session)
+                }
+            },
+        );
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(sessions, vec![1, 2]);
+
+        // A new session closed as well is not retried again.
+        let mut reopened = 0;
+        let mut calls = 0;
+        let result = retry_on_session_closed::<_, ()>(
+            &1,
+            || {
+                reopened += 1;
+                Ok(2)
+            },
+            |_: &u32| {
+                calls += 1;
+                Err(Error::Pkcs11(RvError::SessionHandleInvalid))
+            },
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            ResponseStatus::PsaErrorCommunicationFailure
+        );
+        assert_eq!((reopened, calls), (1, 2));
+    }
+
+    #[test]
+    fn retry_after_session_count() {
+        let mut attempts = 0;
+        let result = retry_on_session_count(|| {
+            attempts += 1;
+            if attempts == 1 {
+                Err(Error::Pkcs11(RvError::SessionCount))
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn give_up_after_session_count_attempts() {
+        let mut attempts = 0;
+        let result: cryptoki::Result<()> = retry_on_session_count(|| {
+            attempts += 1;
+            Err(Error::Pkcs11(RvError::SessionCount))
+        });
+
+        assert!(matches!(result, Err(Error::Pkcs11(RvError::SessionCount))));
+        assert_eq!(attempts, SESSION_COUNT_ATTEMPTS);
+    }
+
+    #[test]
+    fn retry_after_relogin() {
+        let mut attempts = 0;
+        let mut logins = 0;
+        let result = retry_on_user_not_logged_in(
+            true,
+            || {
+                logins += 1;
+                Ok(())
+            },
+            || {
+                attempts += 1;
+                if attempts == 1 {
+                    Err(Error::Pkcs11(RvError::UserNotLoggedIn))
+                } else {
+                    Ok(attempts)
+                }
+            },
+        );
+
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(logins, 1);
+    }
+
+    #[test]
+    fn no_relogin_unless_configured() {
+        let mut attempts = 0;
+        let result: cryptoki::Result<()> = retry_on_user_not_logged_in(
+            false,
+            || panic!("Logged in again"),
+            || {
+                attempts += 1;
+                Err(Error::Pkcs11(RvError::UserNotLoggedIn))
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err(Error::Pkcs11(RvError::UserNotLoggedIn))
+        ));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn valid_public_exponents() {
+        assert_eq!(
+            public_exponent_bytes(65537).unwrap(),
+            PUBLIC_EXPONENT.to_vec()
+        );
+        assert_eq!(public_exponent_bytes(3).unwrap(), vec![0x03]);
+    }
+
+    #[test]
+    fn invalid_public_exponents() {
+        assert!(public_exponent_bytes(65536).is_none());
+        assert!(public_exponent_bytes(1).is_none());
+    }
+
+    #[test]
+    fn disallowed_hashes() {
+        let sha1 = AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: Hash::Sha1.into(),
+        };
+        let sha256 = AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: Hash::Sha256.into(),
+        };
+
+        assert!(!is_hash_allowed(sha1, &[Hash::Sha1]));
+        assert!(is_hash_allowed(sha256, &[Hash::Sha1]));
+        assert!(is_hash_allowed(sha1, &[]));
+    }
+
+    #[test]
+    fn integer_bits_ignores_leading_zeros() {
+        assert_eq!(integer_bits(&[]), 0);
+        assert_eq!(integer_bits(&[0, 0]), 0);
+        assert_eq!(integer_bits(&[0x01, 0x00, 0x01]), 17);
+        assert_eq!(integer_bits(&[0x00, 0x80, 0x00]), 16);
+        assert_eq!(integer_bits(&[0xff; 256]), 2048);
+    }
+
+    #[test]
+    fn parallel_lookup_finds_drifted_key() {
+        let key_ids: Vec<u32> = (0..5000).collect();
+        let on_token: HashSet<u32> = key_ids.iter().copied().filter(|id| *id != 4321).collect();
+        let sessions = AtomicUsize::new(0);
+
+        let found = parallel_with_sessions(
+            &key_ids,
+            8,
+            || Ok(sessions.fetch_add(1, Ordering::Relaxed)),
+            |_, key_id| Ok(on_token.contains(key_id)),
+        )
+        .unwrap();
+
+        assert_eq!(sessions.load(Ordering::Relaxed), 8);
+        assert_eq!(found.len(), key_ids.len());
+        let missing: Vec<u32> = key_ids
+            .iter()
+            .zip(found)
+            .filter(|(_, found)| !found)
+            .map(|(key_id, _)| *key_id)
+            .collect();
+        assert_eq!(missing, vec![4321]);
+    }
+
+    #[test]
+    fn parallel_lookup_reports_errors() {
+        let key_ids: Vec<u32> = (0..100).collect();
+
+        assert_eq!(
+            parallel_with_sessions(
+                &key_ids,
+                4,
+                || Ok(()),
+                |_, key_id| if *key_id == 42 {
+                    Err(ResponseStatus::PsaErrorCommunicationFailure)
+                } else {
+                    Ok(true)
+                },
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorCommunicationFailure
+        );
+        assert!(
+            parallel_with_sessions(&[0u32; 0], 0, || Ok(()), |_, _| Ok(true))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn public_key_returned_for_private_key_request() {
+        assert_eq!(
+            check_object_class(&KeyPairType::PrivateKey, Some(ObjectClass::PUBLIC_KEY))
+                .unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+        assert!(check_object_class(&KeyPairType::PrivateKey, None).is_ok());
+        assert!(check_object_class(&KeyPairType::PublicKey, Some(ObjectClass::PUBLIC_KEY)).is_ok());
+        assert_eq!(
+            check_object_class(&KeyPairType::PublicKey, None).unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+        assert!(check_object_class(&KeyPairType::Any, Some(ObjectClass::PUBLIC_KEY)).is_ok());
+    }
+
+    #[test]
+    fn oaep_plaintext_size_limit() {
+        let oaep = AsymmetricEncryption::RsaOaep {
+            hash_alg: Hash::Sha256,
+        };
+        // 256 - 2 * 32 - 2
+        assert!(check_plaintext_len(oaep, 2048, 190).is_ok());
+        assert_eq!(
+            check_plaintext_len(oaep, 2048, 191).unwrap_err(),
+            ResponseStatus::PsaErrorInvalidArgument
+        );
+        assert!(check_plaintext_len(AsymmetricEncryption::RsaPkcs1v15Crypt, 2048, 245).is_ok());
+        assert!(check_plaintext_len(AsymmetricEncryption::RsaPkcs1v15Crypt, 2048, 246).is_err());
+    }
+
+    #[test]
+    fn ciphertext_size_limit() {
+        assert!(check_ciphertext_len(1024, 128).is_ok());
+        assert_eq!(
+            check_ciphertext_len(1024, 129).unwrap_err(),
+            ResponseStatus::PsaErrorInvalidArgument
+        );
+    }
+
+    #[test]
+    fn sanitize_labels() {
+        assert_eq!(
+            sanitize_label("legacy-hsm/key 42").unwrap(),
+            b"legacy-hsm/key 42"
+        );
+        assert_eq!(sanitize_label("clé").unwrap(), "clé".as_bytes());
+        for label in &["", "new\nline", "nul\0", &"x".repeat(65)] {
+            assert_eq!(
+                sanitize_label(label).unwrap_err(),
+                ResponseStatus::PsaErrorInvalidArgument
+            );
+        }
+    }
+
+    fn usage_flags() -> UsageFlags {
+        UsageFlags {
+            export: false,
+            copy: false,
+            cache: false,
+            encrypt: false,
+            decrypt: false,
+            sign_message: false,
+            verify_message: false,
+            sign_hash: false,
+            verify_hash: false,
+            derive: false,
+        }
+    }
+
+    #[test]
+    fn separate_key_pair_policies() {
+        let public = UsageFlags {
+            verify_hash: true,
+            encrypt: true,
+            ..usage_flags()
+        };
+        let private = UsageFlags {
+            sign_hash: true,
+            ..usage_flags()
+        };
+        check_key_pair_policies(public, private).unwrap();
+
+        let mut pub_template = Vec::new();
+        let mut priv_template = Vec::new();
+        key_pair_policies_to_pkcs11_attributes(
+            public,
+            private,
+            &mut pub_template,
+            &mut priv_template,
+        );
+        let pub_template: Vec<String> = pub_template.iter().map(|a| format!("{:?}", a)).collect();
+        let priv_template: Vec<String> = priv_template.iter().map(|a| format!("{:?}", a)).collect();
+        assert_eq!(
+            pub_template,
+            vec!["Verify(True)", "Encrypt(True)", "Copyable(False)"]
+        );
+        assert_eq!(
+            priv_template,
+            vec![
+                "Sign(True)",
+                "Decrypt(False)",
+                "Derive(False)",
+                "Extractable(False)",
+                "Sensitive(False)",
+                "Copyable(False)"
+            ]
+        );
+
+        let stored = usage_flags_union(public, private);
+        assert!(stored.sign_hash && stored.verify_hash && stored.encrypt && !stored.decrypt);
+    }
+
+    #[test]
+    fn inconsistent_key_pair_policies() {
+        let verify_only = UsageFlags {
+            verify_hash: true,
+            ..usage_flags()
+        };
+        let decrypt = UsageFlags {
+            decrypt: true,
+            ..usage_flags()
+        };
+        assert_eq!(
+            check_key_pair_policies(verify_only, decrypt).unwrap_err(),
+            ResponseStatus::PsaErrorInvalidArgument
+        );
+        assert!(check_key_pair_policies(decrypt, usage_flags()).is_err());
+        assert!(check_key_pair_policies(usage_flags(), verify_only).is_err());
+    }
+
+    #[test]
+    fn key_name_rules() {
+        let rules = KeyNameRules::default();
+        assert!(rules.check("my key").is_ok());
+        assert!(rules.check(&"k".repeat(64)).is_ok());
+        for key_name in &["", &"k".repeat(65), "tab\tkey"] {
+            assert_eq!(
+                rules.check(key_name).unwrap_err(),
+                ResponseStatus::PsaErrorInvalidArgument
+            );
+        }
+
+        let rules = KeyNameRules {
+            reserved_chars: vec!['/', ':'],
+            ..Default::default()
+        };
+        assert!(rules.check("app-key").is_ok());
+        assert_eq!(
+            rules.check("tenant:key").unwrap_err(),
+            ResponseStatus::PsaErrorInvalidArgument
+        );
+    }
+
+    #[test]
+    fn public_export_reads_public_attributes_only() {
+        assert!(check_public_attributes(&RSA_PUBLIC_KEY_ATTRIBUTES).is_ok());
+        assert_eq!(
+            check_public_attributes(&[AttributeType::Modulus, AttributeType::Value]).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        assert_eq!(
+            check_public_attributes(&[AttributeType::Prime]).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
+
+    #[test]
+    fn unavailable_public_exponent() {
+        // The exponent was reported unavailable and left out of the attributes read.
+        assert_eq!(
+            rsa_public_key_parts(vec![Attribute::Modulus(vec![0xc5; 256])]).unwrap_err(),
+            ResponseStatus::PsaErrorCommunicationFailure
+        );
+        assert_eq!(
+            rsa_public_key_parts(vec![Attribute::PublicExponent(vec![1, 0, 1])]).unwrap_err(),
+            ResponseStatus::PsaErrorCommunicationFailure
+        );
+
+        let (modulus, public_exponent) = rsa_public_key_parts(vec![
+            Attribute::PublicExponent(vec![1, 0, 1]),
+            Attribute::Modulus(vec![0xc5; 256]),
+        ])
+        .unwrap();
+        assert_eq!(modulus, vec![0xc5; 256]);
+        assert_eq!(public_exponent, vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn generated_key_id_ignored_by_token() {
+        assert!(check_key_id(
+            KeyId::from(0x1234_5678),
+            vec![Attribute::Id(vec![0x12, 0x34, 0x56, 0x78])]
+        )
+        .is_ok());
+        let wide_id = KeyId::from_bytes(&[0x12; 8]).unwrap();
+        assert!(check_key_id(wide_id, vec![Attribute::Id(vec![0x12; 8])]).is_ok());
+        // Token ignoring the ID of the template: no ID or another one is read back.
+        assert_eq!(
+            check_key_id(KeyId::from(0x1234_5678), Vec::new()).unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+        assert_eq!(
+            check_key_id(
+                KeyId::from(0x1234_5678),
+                vec![Attribute::Id(vec![0, 0, 0, 1])]
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+    }
+
+    #[test]
+    fn zero_length_modulus_is_corrupt() {
+        // A broken object on the token reporting an empty modulus.
+        assert_eq!(
+            rsa_public_key_parts(vec![
+                Attribute::Modulus(Vec::new()),
+                Attribute::PublicExponent(vec![1, 0, 1]),
+            ])
+            .unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+        assert_eq!(
+            rsa_public_key_parts(vec![
+                Attribute::Modulus(vec![0xc5; 256]),
+                Attribute::PublicExponent(Vec::new()),
+            ])
+            .unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+    }
+
+    #[test]
+    fn key_type_mismatch_is_storage_failure() {
+        check_key_type(Type::RsaKeyPair, Some(KeyType::RSA)).unwrap();
+        check_key_type(Type::RsaPublicKey, Some(KeyType::RSA)).unwrap();
+        let ecc = Type::EccKeyPair {
+            curve_family: EccFamily::SecpR1,
+        };
+        check_key_type(ecc, None).unwrap();
+
+        // An RSA key replaced on the token by an object of another type, and the reverse.
+        assert_eq!(
+            check_key_type(Type::RsaKeyPair, None).unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+        assert_eq!(
+            check_key_type(ecc, Some(KeyType::RSA)).unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+    }
+
+    #[test]
+    fn key_size_mismatch_is_storage_failure() {
+        let mut modulus = vec![0xC5; 256];
+        check_key_size(2048, Some(&modulus)).unwrap();
+        check_key_size(0, Some(&modulus)).unwrap();
+
+        // The key was replaced on the token by a smaller one.
+        modulus.truncate(128);
+        assert_eq!(
+            check_key_size(2048, Some(&modulus)).unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+        assert_eq!(
+            check_key_size(2048, None).unwrap_err(),
+            ResponseStatus::PsaErrorStorageFailure
+        );
+    }
+
+    #[test]
+    fn oaep_label_is_the_source_data() {
+        let oaep = AsymmetricEncryption::RsaOaep {
+            hash_alg: Hash::Sha1,
+        };
+        let label = b"some random label";
+
+        match encryption_mechanism(oaep, Some(label)).unwrap() {
+            Mechanism::RsaPkcsOaep(params) => {
+                assert_eq!(params.source_data, label.as_ptr() as *const c_void);
+                assert_eq!(params.source_data_len, Ulong::from(label.len() as u64));
+            }
+            mechanism => panic!("Unexpected mechanism {:?}", mechanism),
+        }
+        for label in vec![None, Some(&[][..])] {
+            match encryption_mechanism(oaep, label).unwrap() {
+                Mechanism::RsaPkcsOaep(params) => {
+                    assert!(params.source_data.is_null());
+                    assert_eq!(params.source_data_len, Ulong::from(0));
+                }
+                mechanism => panic!("Unexpected mechanism {:?}", mechanism),
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::KeyId;
+use parsec_interface::operations::psa_algorithm::AsymmetricSignature;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Bounded cache of the successful signature verifications, to avoid asking the token again to
+/// verify the same signature.
+///
+/// The full inputs are kept, rather than a digest of them, so that a collision can never make an
+/// invalid signature pass. When full, the oldest verification is evicted.
+#[derive(Debug)]
+pub(super) struct VerifyCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    verified: HashSet<VerifiedSignature>,
+    order: VecDeque<VerifiedSignature>,
+}
+
+/// Inputs of a successful verification.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(super) struct VerifiedSignature {
+    key_id: KeyId,
+    alg: Vec<u8>,
+    hash: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl VerifiedSignature {
+    pub(super) fn new(
+        key_id: KeyId,
+        alg: AsymmetricSignature,
+        hash: &[u8],
+        signature: &[u8],
+    ) -> VerifiedSignature {
+        VerifiedSignature {
+            key_id,
+            // Serialising an algorithm can not fail.
+            alg: bincode::serialize(&alg).unwrap_or_default(),
+            hash: hash.to_vec(),
+            signature: signature.to_vec(),
+        }
+    }
+}
+
+impl VerifyCache {
+    /// Create a cache keeping at most `capacity` verifications. A capacity of 0 disables it.
+    pub(super) fn new(capacity: usize) -> VerifyCache {
+        VerifyCache {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Check if the same verification already succeeded.
+    pub(super) fn contains(&self, signature: &VerifiedSignature) -> bool {
+        self.capacity > 0
+            && self
+                .entries
+                .lock()
+                .expect("Verify cache lock poisoned")
+                .verified
+                .contains(signature)
+    }
+
+    /// Remember a successful verification.
+    pub(super) fn insert(&self, signature: VerifiedSignature) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("Verify cache lock poisoned");
+        if !entries.verified.insert(signature.clone()) {
+            return;
+        }
+        entries.order.push_back(signature);
+        if entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                let _ = entries.verified.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget all the verifications made with a key, when it is destroyed.
+    pub(super) fn invalidate_key(&self, key_id: KeyId) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("Verify cache lock poisoned");
+        entries
+            .verified
+            .retain(|signature| signature.key_id != key_id);
+        entries.order.retain(|signature| signature.key_id != key_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{VerifiedSignature, VerifyCache};
+    use crate::providers::pkcs11::KeyId;
+    use parsec_interface::operations::psa_algorithm::{AsymmetricSignature, Hash, SignHash};
+
+    const ALG: AsymmetricSignature = AsymmetricSignature::RsaPkcs1v15Sign {
+        hash_alg: SignHash::Specific(Hash::Sha256),
+    };
+
+    #[test]
+    fn repeated_verify_hits_cache() {
+        let cache = VerifyCache::new(4);
+        cache.insert(VerifiedSignature::new(
+            KeyId::from(1),
+            ALG,
+            &[0xaa; 32],
+            &[0x55; 128],
+        ));
+
+        assert!(cache.contains(&VerifiedSignature::new(
+            KeyId::from(1),
+            ALG,
+            &[0xaa; 32],
+            &[0x55; 128]
+        )));
+        assert!(!cache.contains(&VerifiedSignature::new(
+            KeyId::from(1),
+            ALG,
+            &[0xaa; 32],
+            &[0x56; 128]
+        )));
+        assert!(!cache.contains(&VerifiedSignature::new(
+            KeyId::from(2),
+            ALG,
+            &[0xaa; 32],
+            &[0x55; 128]
+        )));
+    }
+
+    #[test]
+    fn cache_is_bounded_and_invalidated() {
+        let cache = VerifyCache::new(2);
+        for key_id in 1..=3 {
+            cache.insert(VerifiedSignature::new(
+                KeyId::from(key_id),
+                ALG,
+                &[0; 32],
+                &[0; 128],
+            ));
+        }
+        assert!(!cache.contains(&VerifiedSignature::new(
+            KeyId::from(1),
+            ALG,
+            &[0; 32],
+            &[0; 128]
+        )));
+        assert!(cache.contains(&VerifiedSignature::new(
+            KeyId::from(3),
+            ALG,
+            &[0; 32],
+            &[0; 128]
+        )));
+
+        cache.invalidate_key(KeyId::from(3));
+        assert!(!cache.contains(&VerifiedSignature::new(
+            KeyId::from(3),
+            ALG,
+            &[0; 32],
+            &[0; 128]
+        )));
+        assert!(cache.contains(&VerifiedSignature::new(
+            KeyId::from(2),
+            ALG,
+            &[0; 32],
+            &[0; 128]
+        )));
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let cache = VerifyCache::new(0);
+        cache.insert(VerifiedSignature::new(
+            KeyId::from(1),
+            ALG,
+            &[0; 32],
+            &[0; 128],
+        ));
+
+        assert!(!cache.contains(&VerifiedSignature::new(
+            KeyId::from(1),
+            ALG,
+            &[0; 32],
+            &[0; 128]
+        )));
+    }
+}
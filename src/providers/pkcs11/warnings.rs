@@ -0,0 +1,90 @@
+// Copyright 2021 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use log::warn;
+use std::fmt;
+
+/// Caveat of an operation which succeeded nonetheless.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OperationWarning {
+    /// An existing key mapping was replaced by a new one.
+    MappingOverwritten {
+        /// Name of the application owning the key
+        app_name: String,
+        /// Name of the key
+        key_name: String,
+    },
+}
+
+impl fmt::Display for OperationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperationWarning::MappingOverwritten { app_name, key_name } => {
+                if crate::utils::GlobalConfig::log_error_details() {
+                    write!(
+                        f,
+                        "the mapping of key \"{}\" of \"{}\" was overwritten",
+                        key_name, app_name
+                    )
+                } else {
+                    write!(f, "a key mapping was overwritten")
+                }
+            }
+        }
+    }
+}
+
+/// Warnings accumulated during one operation.
+///
+/// Warnings are always logged. They are only kept, to be handed back to the caller, if the
+/// accumulator was created with `Warnings::collecting`.
+#[derive(Debug, Default)]
+pub struct Warnings {
+    collect: bool,
+    warnings: Vec<OperationWarning>,
+}
+
+impl Warnings {
+    /// Create an accumulator keeping the warnings.
+    pub fn collecting() -> Self {
+        Warnings {
+            collect: true,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Log a warning and keep it if collecting.
+    pub fn push(&mut self, warning: OperationWarning) {
+        warn!("Operation succeeded but {}.", warning);
+        if self.collect {
+            self.warnings.push(warning);
+        }
+    }
+
+    /// Get the warnings kept so far.
+    pub fn into_vec(self) -> Vec<OperationWarning> {
+        self.warnings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OperationWarning, Warnings};
+
+    fn overwritten() -> OperationWarning {
+        OperationWarning::MappingOverwritten {
+            app_name: "app".to_string(),
+            key_name: "key".to_string(),
+        }
+    }
+
+    #[test]
+    fn warnings_kept_only_when_collecting() {
+        let mut collected = Warnings::collecting();
+        collected.push(overwritten());
+        assert_eq!(collected.into_vec(), vec![overwritten()]);
+
+        let mut logged = Warnings::default();
+        logged.push(overwritten());
+        assert!(logged.into_vec().is_empty());
+    }
+}
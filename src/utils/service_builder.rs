@@ -309,6 +309,7 @@ unsafe fn get_provider(
             slot_number,
             user_pin,
             software_public_operations,
+            options,
             ..
         } => {
             use std::convert::TryInto;
@@ -320,7 +321,28 @@ unsafe fn get_provider(
                     .with_pkcs11_library_path(library_path.clone())
                     .with_slot_number((*slot_number).try_into()?)
                     .with_user_pin(user_pin.clone())
+                    .with_user_pin_env(options.user_pin_env.clone())
+                    .with_user_pin_file(options.user_pin_file.clone())
                     .with_software_public_operations(*software_public_operations)
+                    .with_rsa_public_exponent(options.rsa_public_exponent)
+                    .with_check_imported_keys(options.check_imported_keys)
+                    .with_disallowed_hashes(options.disallowed_hashes.clone())
+                    .with_startup_consistency_check(options.startup_consistency_check.clone())
+                    .with_startup_consistency_check_threads(
+                        options.startup_consistency_check_threads,
+                    )
+                    .with_key_id_discrepancy(options.key_id_discrepancy.clone())
+                    .with_verify_cache_size(options.verify_cache_size)
+                    .with_key_name_max_length(options.key_name_max_length)
+                    .with_key_name_reserved_characters(options.key_name_reserved_characters.clone())
+                    .with_sign_replay_window(options.sign_replay_window)
+                    .with_check_generated_key_ids(options.check_generated_key_ids)
+                    .with_missing_user_pin(options.missing_user_pin.clone())
+                    .with_relogin(options.relogin)
+                    .with_check_key_types(options.check_key_types)
+                    .with_deterministic_key_ids(options.deterministic_key_ids)
+                    .with_key_id_width(options.key_id_width)
+                    .with_read_only(options.read_only)
                     .build()?,
             ))
         }